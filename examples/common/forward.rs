@@ -0,0 +1,64 @@
+//! Forwarding of the requests received from the SVSM to the KBS, shared by
+//! the examples acting as proxy.
+
+use log::debug;
+use reference_kbc::client_proxy::{BodyEncoding, HttpMethod, Request, Response, RESPONSE_HEADERS};
+use reqwest::{blocking::Client, Method, StatusCode};
+
+/// Sends `req` to the KBS at `url`, returning its response to the SVSM, which
+/// supports the `capabilities` negotiated during the handshake.
+///
+/// If the body of the response cannot be read, a 502 response carrying the
+/// error is returned instead.
+pub fn forward_request(
+    http_client: &Client,
+    url: &str,
+    req: &Request,
+    capabilities: u32,
+) -> anyhow::Result<Response> {
+    let url_req = url.to_owned() + &req.endpoint;
+    let method = match req.method {
+        HttpMethod::GET => Method::GET,
+        HttpMethod::POST => Method::POST,
+        HttpMethod::PUT => Method::PUT,
+        HttpMethod::DELETE => Method::DELETE,
+        HttpMethod::PATCH => Method::PATCH,
+        HttpMethod::HEAD => Method::HEAD,
+    };
+    let mut http_req = http_client.request(method, url_req);
+    if !req.query.is_empty() {
+        http_req = http_req.query(&req.query);
+    }
+    for (name, value) in &req.headers {
+        http_req = http_req.header(name, value);
+    }
+    if req.encoding == BodyEncoding::Base64 {
+        http_req = http_req.body(req.body_bytes()?);
+    } else if !req.body.is_null() {
+        http_req = http_req.json(&req.body);
+    }
+    debug!("HTTP request - {:#?}", http_req);
+
+    let http_resp = http_req.send()?;
+    debug!("HTTP response - {:#?}", http_resp);
+
+    let mut resp = Response::new(http_resp.status().as_u16(), String::new());
+    for name in RESPONSE_HEADERS {
+        for value in http_resp.headers().get_all(name) {
+            if let Ok(value) = value.to_str() {
+                resp.append_header(name, value);
+            }
+        }
+    }
+    match http_resp.bytes() {
+        Ok(body) => resp.set_body_for(&body, capabilities),
+        Err(e) => {
+            return Ok(Response::new(
+                StatusCode::BAD_GATEWAY.as_u16(),
+                e.to_string(),
+            ))
+        }
+    }
+
+    Ok(resp)
+}
@@ -49,7 +49,8 @@ fn main() {
     attestation.measurement[47] = 24;
 
     let kr = KeybrokerRegistration::new(policy, queries);
-    let registration = ClientRegistration::register(&attestation.measurement, resources, &kr);
+    let registration =
+        ClientRegistration::register(&attestation.measurement, resources.into(), &kr).unwrap();
 
     let resp = client
         .post(url.clone() + "/rvp/registration")
@@ -97,9 +98,7 @@ fn main() {
     };
 
     debug!("Challenge: {:#?}", challenge);
-    let nonce = cs
-        .challenge(serde_json::from_str(&challenge).unwrap())
-        .unwrap();
+    let nonce = cs.challenge(challenge.as_bytes()).unwrap();
 
     info!("Nonce: {}", nonce);
 
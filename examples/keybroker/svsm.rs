@@ -1,5 +1,8 @@
 extern crate reference_kbc;
 
+#[path = "../common/forward.rs"]
+mod forward;
+
 use std::{
     env, fs::read_to_string, os::unix::net::UnixStream, path::PathBuf, str::FromStr, thread,
 };
@@ -8,8 +11,8 @@ use base64ct::{Base64, Encoding};
 use log::{debug, error, info};
 use reference_kbc::{
     client_proxy::{
-        unix::UnixConnection, Error as CPError, HttpMethod, Proxy, ProxyRequest, Request,
-        RequestType, Response,
+        unix::UnixConnection, Error as CPError, Proxy, ProxyRequest, Request,
+        SUPPORTED_CAPABILITIES,
     },
     client_registration::ClientRegistration,
     client_session::ClientSession,
@@ -17,14 +20,22 @@ use reference_kbc::{
         keybroker::{KeybrokerClientSnp, KeybrokerRegistration},
         SnpGeneration,
     },
+    secret::SecretBytes,
 };
 use rsa::{traits::PublicKeyParts, Pkcs1v15Encrypt, RsaPrivateKey, RsaPublicKey};
-use serde_json::{from_str, json};
+use serde_json::from_str;
 use sev::firmware::guest::AttestationReport;
 use sha2::{Digest, Sha512};
 
+use crate::forward::forward_request;
+
 fn svsm(socket: UnixStream, mut attestation: AttestationReport) {
-    let mut proxy = Proxy::new(Box::new(UnixConnection(socket)));
+    let mut proxy = Proxy::new(UnixConnection(socket));
+
+    if let Err(e) = proxy.handshake(SUPPORTED_CAPABILITIES) {
+        error!("Handshake error - {e}");
+        return;
+    }
 
     let mut rng = rand::thread_rng();
     let bits = 2048;
@@ -34,22 +45,21 @@ fn svsm(socket: UnixStream, mut attestation: AttestationReport) {
     let mut snp = KeybrokerClientSnp::new(SnpGeneration::Milan);
     let mut cs = ClientSession::new();
 
-    let request = cs.request(&snp).unwrap();
-
-    let challenge = match snp.make(&mut proxy, RequestType::Auth, Some(&request)) {
-        Ok(challenge) => challenge.unwrap(),
+    let challenge = match snp.negotiate(&mut proxy, &mut cs) {
+        Ok(challenge) => challenge,
         Err(e) => {
             error!("Authentication error - {e}");
             return;
         }
     };
 
-    info!("Authentication success - {}", challenge);
+    info!(
+        "Authentication success - {}",
+        String::from_utf8_lossy(&challenge)
+    );
 
-    debug!("Challenge: {:#?}", challenge);
-    let nonce = cs
-        .challenge(serde_json::from_str(&challenge).unwrap())
-        .unwrap();
+    debug!("Challenge: {:#?}", String::from_utf8_lossy(&challenge));
+    let nonce = cs.challenge(&challenge).unwrap();
 
     info!("Nonce: {}", nonce);
 
@@ -72,7 +82,7 @@ fn svsm(socket: UnixStream, mut attestation: AttestationReport) {
 
     let attestation = cs.attestation(key_n_encoded, key_e_encoded, &snp).unwrap();
 
-    if let Err(e) = snp.make(&mut proxy, RequestType::Attest, Some(&attestation)) {
+    if let Err(e) = snp.attest(&mut proxy, &attestation) {
         error!("Attestation error - {e}");
         return;
     }
@@ -81,22 +91,26 @@ fn svsm(socket: UnixStream, mut attestation: AttestationReport) {
 
     info!("Fetching LUKS passphrase");
 
-    let key = match snp.make(&mut proxy, RequestType::Key, None) {
-        Ok(key) => key.unwrap(),
+    let key = match snp.key(&mut proxy) {
+        Ok(key) => key,
         Err(e) => {
             error!("Key fetch error - {e}");
             return;
         }
     };
 
-    debug!("Key fetch success - {}", key);
+    debug!("Key fetch success");
 
-    let secret = cs.secret(key, &snp).unwrap();
-    let decrypted = priv_key.decrypt(Pkcs1v15Encrypt, &secret).unwrap();
+    let secret = cs.secret(key.expose_secret(), &snp).unwrap();
+    let decrypted = SecretBytes::new(
+        priv_key
+            .decrypt(Pkcs1v15Encrypt, secret.expose_secret())
+            .unwrap(),
+    );
 
     info!(
         "Decrypted passphrase: {}",
-        String::from_utf8(decrypted).unwrap()
+        String::from_utf8_lossy(decrypted.expose_secret())
     );
 }
 
@@ -127,7 +141,8 @@ fn main() {
     attestation.measurement[47] = 24;
 
     let kr = KeybrokerRegistration::new(policy, queries);
-    let registration = ClientRegistration::register(&attestation.measurement, resources, &kr);
+    let registration =
+        ClientRegistration::register(&attestation.measurement, resources.into(), &kr).unwrap();
 
     let resp = client
         .post(url_server.clone() + "/rvp/registration")
@@ -155,7 +170,9 @@ fn main() {
     let (socket, remote_socket) = UnixStream::pair().unwrap();
     let svsm = thread::spawn(move || svsm(remote_socket, attestation));
 
-    let mut proxy = Proxy::new(Box::new(UnixConnection(socket)));
+    let mut proxy = Proxy::new(UnixConnection(socket));
+
+    let caps = proxy.accept_handshake(SUPPORTED_CAPABILITIES).unwrap();
 
     loop {
         let data = match proxy.read_json() {
@@ -171,21 +188,9 @@ fn main() {
         };
         let req: Request = serde_json::from_value(data).unwrap();
 
-        let url = url_server.clone() + &req.endpoint;
-        let http_req = match req.method {
-            HttpMethod::GET => client.get(url).json(&req.body),
-            HttpMethod::POST => client.post(url).json(&req.body),
-        };
-        debug!("HTTP request - {:#?}", http_req);
-
-        let http_resp = http_req.send().unwrap();
-        debug!("HTTP response - {:#?}", http_resp);
-
-        let resp = Response {
-            status: http_resp.status().as_u16(),
-            body: http_resp.text().unwrap_or(String::new()),
-        };
-        if let Err(e) = proxy.write_json(&json!(resp)) {
+        let mut resp = forward_request(&client, &url_server, &req, caps).unwrap();
+        resp.id = req.id;
+        if let Err(e) = proxy.write_message(&resp) {
             error!("{e}");
             break;
         }
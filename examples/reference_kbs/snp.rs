@@ -39,9 +39,10 @@ fn main() {
     let rkr = ReferenceKBSRegistration::new(workload_id.clone());
     let registration = ClientRegistration::register(
         &attestation.measurement,
-        "secret passphrase".to_string(),
+        "secret passphrase".to_string().into(),
         &rkr,
-    );
+    )
+    .unwrap();
 
     let resp = client
         .post(url.clone() + "/kbs/v0/register_workload")
@@ -85,9 +86,7 @@ fn main() {
     };
 
     debug!("Challenge: {:#?}", challenge);
-    let nonce = cs
-        .challenge(serde_json::from_str(&challenge).unwrap())
-        .unwrap();
+    let nonce = cs.challenge(challenge.as_bytes()).unwrap();
 
     info!("Nonce: {}", nonce);
 
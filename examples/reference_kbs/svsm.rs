@@ -1,12 +1,15 @@
 extern crate reference_kbc;
 
+#[path = "../common/forward.rs"]
+mod forward;
+
 use std::{env, os::unix::net::UnixStream, thread};
 
 use log::{debug, error, info};
 use reference_kbc::{
     client_proxy::{
-        unix::UnixConnection, Error as CPError, HttpMethod, Proxy, ProxyRequest, Request,
-        RequestType, Response,
+        unix::UnixConnection, Error as CPError, Proxy, ProxyRequest, Request,
+        SUPPORTED_CAPABILITIES,
     },
     client_registration::ClientRegistration,
     client_session::ClientSession,
@@ -16,12 +19,18 @@ use reference_kbc::{
     },
 };
 use rsa::{traits::PublicKeyParts, RsaPrivateKey, RsaPublicKey};
-use serde_json::json;
 use sev::firmware::guest::AttestationReport;
 use sha2::{Digest, Sha512};
 
+use crate::forward::forward_request;
+
 fn svsm(socket: UnixStream, workload_id: String, mut attestation: AttestationReport) {
-    let mut proxy = Proxy::new(Box::new(UnixConnection(socket)));
+    let mut proxy = Proxy::new(UnixConnection(socket));
+
+    if let Err(e) = proxy.handshake(SUPPORTED_CAPABILITIES) {
+        error!("Handshake error - {e}");
+        return;
+    }
 
     let mut rng = rand::thread_rng();
     let bits = 2048;
@@ -31,22 +40,21 @@ fn svsm(socket: UnixStream, workload_id: String, mut attestation: AttestationRep
     let mut snp = ReferenceKBSClientSnp::new(SnpGeneration::Milan, workload_id);
     let mut cs = ClientSession::new();
 
-    let request = cs.request(&snp).unwrap();
-
-    let challenge = match snp.make(&mut proxy, RequestType::Auth, Some(&request)) {
-        Ok(challenge) => challenge.unwrap(),
+    let challenge = match snp.negotiate(&mut proxy, &mut cs) {
+        Ok(challenge) => challenge,
         Err(e) => {
             error!("Authentication error - {e}");
             return;
         }
     };
 
-    info!("Authentication success - {}", challenge);
+    info!(
+        "Authentication success - {}",
+        String::from_utf8_lossy(&challenge)
+    );
 
-    debug!("Challenge: {:#?}", challenge);
-    let nonce = cs
-        .challenge(serde_json::from_str(&challenge).unwrap())
-        .unwrap();
+    debug!("Challenge: {:#?}", String::from_utf8_lossy(&challenge));
+    let nonce = cs.challenge(&challenge).unwrap();
 
     info!("Nonce: {}", nonce);
 
@@ -69,7 +77,7 @@ fn svsm(socket: UnixStream, workload_id: String, mut attestation: AttestationRep
 
     let attestation = cs.attestation(key_n_encoded, key_e_encoded, &snp).unwrap();
 
-    if let Err(e) = snp.make(&mut proxy, RequestType::Attest, Some(&attestation)) {
+    if let Err(e) = snp.attest(&mut proxy, &attestation) {
         error!("Attestation error - {e}");
         return;
     }
@@ -97,9 +105,10 @@ fn main() {
     let rkr = ReferenceKBSRegistration::new(workload_id.clone());
     let registration = ClientRegistration::register(
         &attestation.measurement,
-        "secret passphrase".to_string(),
+        "secret passphrase".to_string().into(),
         &rkr,
-    );
+    )
+    .unwrap();
 
     let resp = client
         .post(url_server.clone() + "/kbs/v0/register_workload")
@@ -121,7 +130,9 @@ fn main() {
     let (socket, remote_socket) = UnixStream::pair().unwrap();
     let svsm = thread::spawn(move || svsm(remote_socket, workload_id, attestation));
 
-    let mut proxy = Proxy::new(Box::new(UnixConnection(socket)));
+    let mut proxy = Proxy::new(UnixConnection(socket));
+
+    let caps = proxy.accept_handshake(SUPPORTED_CAPABILITIES).unwrap();
 
     loop {
         let data = match proxy.read_json() {
@@ -137,21 +148,9 @@ fn main() {
         };
         let req: Request = serde_json::from_value(data).unwrap();
 
-        let url = url_server.clone() + &req.endpoint;
-        let http_req = match req.method {
-            HttpMethod::GET => client.get(url).json(&req.body),
-            HttpMethod::POST => client.post(url).json(&req.body),
-        };
-        debug!("HTTP request - {:#?}", http_req);
-
-        let http_resp = http_req.send().unwrap();
-        debug!("HTTP response - {:#?}", http_resp);
-
-        let resp = Response {
-            status: http_resp.status().as_u16(),
-            body: http_resp.text().unwrap_or(String::new()),
-        };
-        if let Err(e) = proxy.write_json(&json!(resp)) {
+        let mut resp = forward_request(&client, &url_server, &req, caps).unwrap();
+        resp.id = req.id;
+        if let Err(e) = proxy.write_message(&resp) {
             error!("{e}");
             break;
         }
@@ -1,68 +1,161 @@
+#[path = "common/forward.rs"]
+mod forward;
+
 use std::{
-    os::unix::net::{UnixListener, UnixStream},
+    net::TcpListener,
+    os::unix::net::UnixListener,
+    sync::mpsc::{self, RecvTimeoutError},
     thread,
+    time::Duration,
 };
 
 use clap::Parser;
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
+#[cfg(feature = "serial")]
+use reference_kbc::client_proxy::serial::{FlowControl, SerialConfig, SerialConnection};
+#[cfg(feature = "vsock")]
+use reference_kbc::client_proxy::vsock::{VsockListener, VMADDR_CID_ANY};
 use reference_kbc::client_proxy::{
-    unix::UnixConnection, Error as CPError, HttpMethod, Proxy, Request, Response,
+    std_clock::StdClock, stdio::StdioConnection, tcp::TcpConnection, unix::UnixConnection,
+    Connection, Error as CPError, Framing, Proxy, Request, Response, DEFAULT_MAX_FRAME_SIZE,
+    SUPPORTED_CAPABILITIES,
 };
 use reqwest::blocking::{Client, ClientBuilder};
-use serde_json::{json, Value};
 use thiserror::Error as ThisError;
 
+use crate::forward::forward_request;
+
 /// Custom error types
 #[derive(Debug, ThisError)]
 pub enum Error {
     #[error("Creation of Unix socket failed - {0}")]
     UnixListen(std::io::Error),
-    #[error("Reading from the Unix socket failed - {0}")]
+    #[error("Creation of TCP socket failed - {0}")]
+    TcpListen(std::io::Error),
+    #[cfg(feature = "serial")]
+    #[error("Opening the serial port failed - {0}")]
+    SerialOpen(std::io::Error),
+    #[cfg(feature = "vsock")]
+    #[error("Creation of vsock socket failed - {0}")]
+    VsockListen(std::io::Error),
+    #[error("Reading from the SVSM connection failed - {0}")]
     ProxyRead(CPError),
+    #[error("Handshake with the SVSM failed - {0}")]
+    ProxyHandshake(CPError),
     #[error("Communication with the HTTP server failed - {0}")]
     HttpCommunication(reqwest::Error),
 }
 
-#[derive(Parser, Debug)]
-#[clap(version, about, long_about = None)]
+#[derive(Parser, Clone, Debug)]
+#[clap(version, about, long_about = None, group(
+    clap::ArgGroup::new("listen")
+        .required(true)
+))]
 struct ProxyArgs {
     /// HTTP url to KBS (e.g. http://server:4242)
     #[clap(long)]
     url: String,
     /// Unix domain socket path to the SVSM serial port
-    #[clap(long)]
-    unix: String,
+    #[clap(long, group = "listen")]
+    unix: Option<String>,
     /// Force Unix domain socket removal before bind
-    #[clap(long, short, default_value_t = false)]
+    #[clap(long, short, default_value_t = false, requires = "unix")]
     force: bool,
+    /// TCP address to listen on for the SVSM serial port (e.g. 127.0.0.1:4343)
+    #[clap(long, group = "listen")]
+    tcp: Option<String>,
+    /// Serve a single SVSM over stdin/stdout (e.g. when started by the VMM)
+    #[clap(long, group = "listen", default_value_t = false)]
+    stdio: bool,
+    /// vsock port to listen on for guest connections
+    #[cfg(feature = "vsock")]
+    #[clap(long, group = "listen")]
+    vsock_port: Option<u32>,
+    /// Local vsock context ID to listen on (default: any)
+    #[cfg(feature = "vsock")]
+    #[clap(long, default_value_t = VMADDR_CID_ANY, requires = "vsock_port")]
+    vsock_cid: u32,
+    /// Serial port (tty device) connected to the SVSM serial port
+    #[cfg(feature = "serial")]
+    #[clap(long, group = "listen")]
+    serial: Option<String>,
+    /// Baud rate of the serial port
+    #[cfg(feature = "serial")]
+    #[clap(long, default_value_t = 115200, requires = "serial")]
+    baud_rate: u32,
+    /// Flow control of the serial port (none, hardware, software)
+    #[cfg(feature = "serial")]
+    #[clap(long, default_value = "none", requires = "serial")]
+    flow_control: FlowControl,
+    /// Maximum size in bytes of the messages exchanged with the SVSM
+    #[clap(long, default_value_t = DEFAULT_MAX_FRAME_SIZE)]
+    max_frame_size: usize,
+    /// Use COBS framing with CRC-32 and retransmission (for lossy links, the
+    /// SVSM must be configured the same way)
+    #[clap(long, default_value_t = false)]
+    cobs: bool,
+    /// Interval in milliseconds of the pings sent to the SVSM while waiting
+    /// for the KBS, so it does not time out (0 to disable)
+    #[clap(long, default_value_t = 5000)]
+    keepalive: u64,
+    /// Drop the SVSM if nothing is received from it for this many
+    /// milliseconds (default: wait forever)
+    #[clap(long)]
+    timeout: Option<u64>,
 }
 
-fn forward_request(http_client: &Client, url: &str, data: Value) -> anyhow::Result<Response> {
-    let req: Request = serde_json::from_value(data)?;
-
-    let url_req = url.to_owned() + &req.endpoint;
-    let http_req = match req.method {
-        HttpMethod::GET => http_client.get(url_req).json(&req.body),
-        HttpMethod::POST => http_client.post(url_req).json(&req.body),
-    };
-    debug!("HTTP request - {:#?}", http_req);
-
-    let http_resp = http_req.send().map_err(Error::HttpCommunication)?;
-    debug!("HTTP response - {:#?}", http_resp);
+/// How often reads of the SVSM connection return to check the timeout.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
 
-    let resp = Response {
-        status: http_resp.status().as_u16(),
-        body: http_resp.text().unwrap_or(String::new()),
-    };
+/// Forwards `req` from a worker thread, pinging the SVSM every `keepalive`
+/// until the KBS answers.
+fn forward_request_keepalive<C: Connection>(
+    proxy: &mut Proxy<C>,
+    http_client: &Client,
+    url: &str,
+    req: &Request,
+    caps: u32,
+    keepalive: Duration,
+) -> anyhow::Result<Response> {
+    thread::scope(|s| {
+        let (tx, rx) = mpsc::channel();
+        s.spawn(move || tx.send(forward_request(http_client, url, req, caps)));
 
-    Ok(resp)
+        loop {
+            match rx.recv_timeout(keepalive) {
+                Ok(resp) => return resp,
+                Err(RecvTimeoutError::Timeout) => proxy.ping()?,
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(anyhow::anyhow!("HTTP worker thread panicked"))
+                }
+            }
+        }
+    })
 }
 
-fn start_proxy(stream: UnixStream, url: String) -> anyhow::Result<()> {
-    let mut proxy = Proxy::new(Box::new(UnixConnection(stream)));
+fn start_proxy<C: Connection>(mut conn: C, config: ProxyArgs) -> anyhow::Result<()> {
+    let url = config.url;
+    if config.timeout.is_some() {
+        if let Err(e) = conn.set_read_timeout(Some(POLL_INTERVAL)) {
+            warn!("Timeout not supported by the SVSM connection - {e}");
+        }
+    }
+
+    let mut proxy = Proxy::new(conn);
+    proxy.set_clock(StdClock::new());
+    proxy.set_timeout(config.timeout);
+    proxy.set_max_frame_size(config.max_frame_size);
+    if config.cobs {
+        proxy.set_framing(Framing::Cobs);
+    }
 
     let http_client = ClientBuilder::new().cookie_store(true).build()?;
 
+    let caps = proxy
+        .accept_handshake(SUPPORTED_CAPABILITIES)
+        .map_err(Error::ProxyHandshake)?;
+    debug!("Negotiated capabilities: {caps:#x}");
+
     info!("Starting HTTP proxy for {url}");
 
     loop {
@@ -77,25 +170,40 @@ fn start_proxy(stream: UnixStream, url: String) -> anyhow::Result<()> {
             }
         };
 
-        let resp = match forward_request(&http_client, &url, data) {
-            Ok(resp) => resp,
+        let resp = match serde_json::from_value::<Request>(data) {
+            Ok(req) => {
+                let resp = match config.keepalive {
+                    0 => forward_request(&http_client, &url, &req, caps),
+                    ms => forward_request_keepalive(
+                        &mut proxy,
+                        &http_client,
+                        &url,
+                        &req,
+                        caps,
+                        Duration::from_millis(ms),
+                    ),
+                };
+                let mut resp = resp.unwrap_or_else(|e| {
+                    error!("{e}");
+                    Response::new(999, e.to_string())
+                });
+                resp.id = req.id;
+                resp
+            }
             Err(e) => {
                 error!("{e}");
-                Response {
-                    status: 999,
-                    body: e.to_string(),
-                }
+                Response::new(999, e.to_string())
             }
         };
 
-        proxy.write_json(&json!(resp))?;
+        proxy.write_message(&resp)?;
     }
 
     Ok(())
 }
 
-fn handle_client(stream: UnixStream, url: String) {
-    if let Err(e) = start_proxy(stream, url) {
+fn handle_client<C: Connection>(conn: C, config: ProxyArgs) {
+    if let Err(e) = start_proxy(conn, config) {
         error!("{e}");
     }
 }
@@ -105,11 +213,40 @@ fn main() -> anyhow::Result<()> {
 
     let config = ProxyArgs::parse();
 
-    if config.force {
-        let _ = std::fs::remove_file(config.unix.clone());
-    }
+    let unix_listener = match &config.unix {
+        Some(path) => {
+            if config.force {
+                let _ = std::fs::remove_file(path);
+            }
+            Some(UnixListener::bind(path).map_err(Error::UnixListen)?)
+        }
+        None => None,
+    };
+
+    let tcp_listener = match &config.tcp {
+        Some(addr) => Some(TcpListener::bind(addr).map_err(Error::TcpListen)?),
+        None => None,
+    };
 
-    let listener = UnixListener::bind(config.unix).map_err(Error::UnixListen)?;
+    #[cfg(feature = "vsock")]
+    let vsock_listener = match config.vsock_port {
+        Some(port) => {
+            Some(VsockListener::bind(config.vsock_cid, port).map_err(Error::VsockListen)?)
+        }
+        None => None,
+    };
+
+    #[cfg(feature = "serial")]
+    let serial_conn = match &config.serial {
+        Some(path) => {
+            let serial_config = SerialConfig {
+                baud_rate: config.baud_rate,
+                flow_control: config.flow_control,
+            };
+            Some(SerialConnection::open(path, &serial_config).map_err(Error::SerialOpen)?)
+        }
+        None => None,
+    };
 
     // We will probably receive a 404 error, but let's try a GET just to raise
     // an error right away and get out if the server is already unreachable.
@@ -118,14 +255,60 @@ fn main() -> anyhow::Result<()> {
         .send()
         .map_err(Error::HttpCommunication)?;
 
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => {
-                let url = config.url.clone();
-                thread::spawn(|| handle_client(stream, url));
+    if let Some(listener) = unix_listener {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let config = config.clone();
+                    thread::spawn(|| handle_client(UnixConnection(stream), config));
+                }
+                Err(e) => {
+                    error!("{e}");
+                }
             }
-            Err(e) => {
-                error!("{e}");
+        }
+    }
+
+    if let Some(listener) = tcp_listener {
+        for stream in listener.incoming() {
+            match stream.and_then(|stream| {
+                let conn = TcpConnection(stream);
+                conn.set_nodelay(true)?;
+                Ok(conn)
+            }) {
+                Ok(conn) => {
+                    let config = config.clone();
+                    thread::spawn(|| handle_client(conn, config));
+                }
+                Err(e) => {
+                    error!("{e}");
+                }
+            }
+        }
+    }
+
+    if config.stdio {
+        handle_client(StdioConnection::stdio(), config.clone());
+    }
+
+    // A serial port is connected to a single guest, so serve it directly
+    #[cfg(feature = "serial")]
+    if let Some(conn) = serial_conn {
+        handle_client(conn, config.clone());
+    }
+
+    #[cfg(feature = "vsock")]
+    if let Some(listener) = vsock_listener {
+        loop {
+            match listener.accept() {
+                Ok((conn, peer)) => {
+                    info!("New vsock connection from CID {}", peer.cid);
+                    let config = config.clone();
+                    thread::spawn(|| handle_client(conn, config));
+                }
+                Err(e) => {
+                    error!("{e}");
+                }
             }
         }
     }
@@ -90,16 +90,16 @@ fn main() -> anyhow::Result<()> {
 
         let kr = KeybrokerRegistration::new(policy, queries);
         let registration =
-            ClientRegistration::register(&hex::decode(config.measurement)?, resources, &kr);
+            ClientRegistration::register(&hex::decode(config.measurement)?, resources.into(), &kr)?;
 
         ("/rvp/registration", registration)
     } else if config.rkbs_args.reference_kbs {
         let rkr = ReferenceKBSRegistration::new(config.rkbs_args.workload_id.unwrap().clone());
         let registration = ClientRegistration::register(
             &hex::decode(config.measurement)?,
-            config.rkbs_args.passphrase.unwrap(),
+            config.rkbs_args.passphrase.unwrap().into(),
             &rkr,
-        );
+        )?;
         ("/kbs/v0/register_workload", registration)
     } else {
         panic!();
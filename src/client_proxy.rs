@@ -1,30 +1,121 @@
+#[cfg(feature = "alloc")]
+use base64ct::{Base64, Encoding};
+#[cfg(feature = "alloc")]
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+#[cfg(feature = "alloc")]
 use serde_json::Value;
+#[cfg(feature = "alloc")]
+use zeroize::Zeroize;
 
-use crate::lib::{fmt, vec, Box, Debug, String, TryFromIntError};
+#[cfg(feature = "alloc")]
+use self::framing::Crc32;
+#[cfg(feature = "alloc")]
+use crate::client_session::{Attestation, ClientSession, Request as KbsRequest, TeeSession};
+use crate::lib::{fmt, Debug, Duration, TryFromIntError};
+#[cfg(feature = "alloc")]
+use crate::lib::{mem, vec, BTreeMap, Box, String, ToString, Vec};
+#[cfg(feature = "alloc")]
+use crate::secret::SecretBytes;
 
 #[derive(Debug)]
 pub enum Error {
+    #[cfg(feature = "alloc")]
     JsonError(serde_json::Error),
     NumError(TryFromIntError),
-    FlushError(anyhow::Error),
-    ReadError(anyhow::Error),
-    WriteError(anyhow::Error),
+    Base64Error(base64ct::Error),
+    FlushError(IoError),
+    ReadError(IoError),
+    WriteError(IoError),
     WriteZero,
     UnexpectedEof,
     Eof,
+    #[cfg(feature = "alloc")]
     HttpError(u16, String),
-    BodyExpected(RequestType),
+    BadMagic([u8; 4]),
+    UnsupportedVersion(u8),
+    UnexpectedFrame,
+    FrameTooLarge(usize, usize),
+    CorruptFrame,
+    DuplicateRequestId(u32),
+    UnknownRequestId(u32),
+    WouldBlock,
+    Timeout,
+    Unsupported,
+    #[cfg(feature = "heapless")]
+    JsonSerError(serde_json_core::ser::Error),
+    #[cfg(feature = "heapless")]
+    JsonDeError(serde_json_core::de::Error),
+    #[cfg(feature = "heapless")]
+    HttpStatus(u16),
+    BufferTooSmall(usize, usize),
+    MissingWorkloadId,
+}
+
+impl Error {
+    /// Stable code of the error, see [`crate::Error::code`].
+    pub fn code(&self) -> u16 {
+        match self {
+            #[cfg(feature = "alloc")]
+            Self::JsonError(_) => 1,
+            Self::NumError(_) => 2,
+            Self::Base64Error(_) => 3,
+            Self::FlushError(_) => 4,
+            Self::ReadError(_) => 5,
+            Self::WriteError(_) => 6,
+            Self::WriteZero => 7,
+            Self::UnexpectedEof => 8,
+            Self::Eof => 9,
+            #[cfg(feature = "alloc")]
+            Self::HttpError(..) => 10,
+            // 11 was the body missing from a request, now checked at compile time
+            Self::BadMagic(_) => 12,
+            Self::UnsupportedVersion(_) => 13,
+            Self::UnexpectedFrame => 14,
+            Self::FrameTooLarge(..) => 15,
+            Self::CorruptFrame => 16,
+            Self::DuplicateRequestId(_) => 17,
+            Self::UnknownRequestId(_) => 18,
+            Self::WouldBlock => 19,
+            Self::Timeout => 20,
+            Self::Unsupported => 21,
+            #[cfg(feature = "heapless")]
+            Self::JsonSerError(_) => 22,
+            #[cfg(feature = "heapless")]
+            Self::JsonDeError(_) => 23,
+            #[cfg(feature = "heapless")]
+            Self::HttpStatus(_) => 24,
+            Self::BufferTooSmall(..) => 25,
+            Self::MissingWorkloadId => 26,
+        }
+    }
 }
 
 #[cfg(feature = "std")]
-impl std::error::Error for Error {}
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            #[cfg(feature = "alloc")]
+            Self::JsonError(e) => Some(e),
+            Self::NumError(e) => Some(e),
+            Self::Base64Error(e) => Some(e),
+            Self::FlushError(e) | Self::ReadError(e) | Self::WriteError(e) => Some(e),
+            #[cfg(feature = "heapless")]
+            Self::JsonSerError(e) => Some(e),
+            #[cfg(feature = "heapless")]
+            Self::JsonDeError(e) => Some(e),
+            _ => None,
+        }
+    }
+}
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
+            #[cfg(feature = "alloc")]
             Self::JsonError(je) => write!(f, "Malformed JSON - {je}"),
             Self::NumError(ne) => write!(f, "Integer converions failed - {ne}"),
+            Self::Base64Error(be) => write!(f, "Malformed base64 body - {be}"),
             Self::FlushError(e) => write!(f, "Flush failed - {e}"),
             Self::ReadError(e) => write!(f, "Read failed - {e}"),
             Self::WriteError(e) => write!(f, "Write failed - {e}"),
@@ -34,13 +125,213 @@ impl fmt::Display for Error {
             ),
             Self::UnexpectedEof => write!(f, "Unexpected EOF while filling the buffer"),
             Self::Eof => write!(f, "Reached end of file"),
+            #[cfg(feature = "alloc")]
             Self::HttpError(status, body) => write!(f, "HTTP error code: {status} - {body}"),
-            Self::BodyExpected(rt) => write!(
+            Self::BadMagic(magic) => write!(f, "Invalid frame magic: {:02x?}", magic),
+            Self::UnsupportedVersion(v) => write!(f, "Unsupported protocol version: {v}"),
+            Self::UnexpectedFrame => write!(f, "Unexpected frame type received"),
+            Self::CorruptFrame => write!(f, "Too many corrupted frames received"),
+            Self::DuplicateRequestId(id) => write!(f, "Request {id} is already in flight"),
+            Self::UnknownRequestId(id) => write!(f, "No request {id} in flight"),
+            Self::WouldBlock => write!(f, "No data available before the read timeout"),
+            Self::Timeout => write!(f, "Timed out waiting for the peer"),
+            Self::Unsupported => write!(f, "Operation not supported by the connection"),
+            #[cfg(feature = "heapless")]
+            Self::JsonSerError(je) => write!(f, "JSON serialization failed - {je}"),
+            #[cfg(feature = "heapless")]
+            Self::JsonDeError(je) => write!(f, "Malformed JSON - {je}"),
+            #[cfg(feature = "heapless")]
+            Self::HttpStatus(status) => write!(f, "HTTP error code: {status}"),
+            Self::BufferTooSmall(needed, size) => write!(
                 f,
-                "Request type {:#?} expects a body, but it was not provided",
-                rt
+                "Message of {needed} bytes does not fit in a buffer of {size} bytes"
             ),
+            Self::MissingWorkloadId => {
+                write!(
+                    f,
+                    "Endpoint requires a workload ID, but the client has none"
+                )
+            }
+            Self::FrameTooLarge(len, max) => {
+                write!(
+                    f,
+                    "Frame of {len} bytes exceeds the maximum size of {max} bytes"
+                )
+            }
+        }
+    }
+}
+
+/// Kind of a transport error, mirroring the relevant [`std::io::ErrorKind`]s
+/// so that connections without the standard library can report them too.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum ErrorKind {
+    NotFound,
+    PermissionDenied,
+    ConnectionRefused,
+    ConnectionReset,
+    ConnectionAborted,
+    NotConnected,
+    AddrInUse,
+    AddrNotAvailable,
+    BrokenPipe,
+    AlreadyExists,
+    InvalidInput,
+    InvalidData,
+    TimedOut,
+    Interrupted,
+    Unsupported,
+    OutOfMemory,
+    Other,
+}
+
+impl ErrorKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::NotFound => "entity not found",
+            Self::PermissionDenied => "permission denied",
+            Self::ConnectionRefused => "connection refused",
+            Self::ConnectionReset => "connection reset",
+            Self::ConnectionAborted => "connection aborted",
+            Self::NotConnected => "not connected",
+            Self::AddrInUse => "address in use",
+            Self::AddrNotAvailable => "address not available",
+            Self::BrokenPipe => "broken pipe",
+            Self::AlreadyExists => "entity already exists",
+            Self::InvalidInput => "invalid input parameter",
+            Self::InvalidData => "invalid data",
+            Self::TimedOut => "timed out",
+            Self::Interrupted => "operation interrupted",
+            Self::Unsupported => "unsupported",
+            Self::OutOfMemory => "out of memory",
+            Self::Other => "other error",
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<std::io::ErrorKind> for ErrorKind {
+    fn from(kind: std::io::ErrorKind) -> Self {
+        use std::io::ErrorKind as K;
+
+        match kind {
+            K::NotFound => Self::NotFound,
+            K::PermissionDenied => Self::PermissionDenied,
+            K::ConnectionRefused => Self::ConnectionRefused,
+            K::ConnectionReset => Self::ConnectionReset,
+            K::ConnectionAborted => Self::ConnectionAborted,
+            K::NotConnected => Self::NotConnected,
+            K::AddrInUse => Self::AddrInUse,
+            K::AddrNotAvailable => Self::AddrNotAvailable,
+            K::BrokenPipe => Self::BrokenPipe,
+            K::AlreadyExists => Self::AlreadyExists,
+            K::InvalidInput => Self::InvalidInput,
+            K::InvalidData => Self::InvalidData,
+            K::TimedOut => Self::TimedOut,
+            K::Interrupted => Self::Interrupted,
+            K::Unsupported => Self::Unsupported,
+            K::OutOfMemory => Self::OutOfMemory,
+            _ => Self::Other,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<ErrorKind> for std::io::ErrorKind {
+    fn from(kind: ErrorKind) -> Self {
+        match kind {
+            ErrorKind::NotFound => Self::NotFound,
+            ErrorKind::PermissionDenied => Self::PermissionDenied,
+            ErrorKind::ConnectionRefused => Self::ConnectionRefused,
+            ErrorKind::ConnectionReset => Self::ConnectionReset,
+            ErrorKind::ConnectionAborted => Self::ConnectionAborted,
+            ErrorKind::NotConnected => Self::NotConnected,
+            ErrorKind::AddrInUse => Self::AddrInUse,
+            ErrorKind::AddrNotAvailable => Self::AddrNotAvailable,
+            ErrorKind::BrokenPipe => Self::BrokenPipe,
+            ErrorKind::AlreadyExists => Self::AlreadyExists,
+            ErrorKind::InvalidInput => Self::InvalidInput,
+            ErrorKind::InvalidData => Self::InvalidData,
+            ErrorKind::TimedOut => Self::TimedOut,
+            ErrorKind::Interrupted => Self::Interrupted,
+            ErrorKind::Unsupported => Self::Unsupported,
+            ErrorKind::OutOfMemory => Self::OutOfMemory,
+            ErrorKind::Other => Self::Other,
+        }
+    }
+}
+
+/// Error reported by a [`Connection`] while reading, writing or flushing.
+///
+/// Besides the [`ErrorKind`], it keeps the OS error code when there is one
+/// (e.g. `errno` on Linux), so the cause of the failure is not lost.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct IoError {
+    kind: ErrorKind,
+    os_error: Option<i32>,
+}
+
+impl IoError {
+    pub fn new(kind: ErrorKind) -> Self {
+        IoError {
+            kind,
+            os_error: None,
+        }
+    }
+
+    pub fn from_os_error(kind: ErrorKind, code: i32) -> Self {
+        IoError {
+            kind,
+            os_error: Some(code),
+        }
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+
+    pub fn os_error(&self) -> Option<i32> {
+        self.os_error
+    }
+}
+
+impl From<ErrorKind> for IoError {
+    fn from(kind: ErrorKind) -> Self {
+        IoError::new(kind)
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<std::io::Error> for IoError {
+    fn from(e: std::io::Error) -> Self {
+        IoError {
+            kind: e.kind().into(),
+            os_error: e.raw_os_error(),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<IoError> for std::io::Error {
+    fn from(e: IoError) -> Self {
+        match e.os_error {
+            Some(code) => std::io::Error::from_raw_os_error(code),
+            None => std::io::Error::from(std::io::ErrorKind::from(e.kind)),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for IoError {}
+
+impl fmt::Display for IoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.kind.as_str())?;
+        if let Some(code) = self.os_error {
+            write!(f, " (os error {code})")?;
         }
+        Ok(())
     }
 }
 
@@ -49,6 +340,7 @@ impl From<Error> for crate::Error {
         Self::CP(e)
     }
 }
+#[cfg(feature = "alloc")]
 impl From<serde_json::Error> for Error {
     fn from(e: serde_json::Error) -> Self {
         Self::JsonError(e)
@@ -61,6 +353,26 @@ impl From<TryFromIntError> for Error {
     }
 }
 
+impl From<base64ct::Error> for Error {
+    fn from(e: base64ct::Error) -> Self {
+        Self::Base64Error(e)
+    }
+}
+
+#[cfg(feature = "heapless")]
+impl From<serde_json_core::ser::Error> for Error {
+    fn from(e: serde_json_core::ser::Error) -> Self {
+        Self::JsonSerError(e)
+    }
+}
+
+#[cfg(feature = "heapless")]
+impl From<serde_json_core::de::Error> for Error {
+    fn from(e: serde_json_core::de::Error) -> Self {
+        Self::JsonDeError(e)
+    }
+}
+
 pub trait Write {
     fn write(&mut self, buf: &[u8]) -> Result<usize, Error>;
     fn flush(&mut self) -> Result<(), Error>;
@@ -107,141 +419,1387 @@ pub trait Read {
     }
 }
 
-pub trait Connection: Write + Read {}
+pub trait Connection: Write + Read {
+    /// Makes `read()` fail with [`Error::WouldBlock`] if no data arrives
+    /// within `timeout`, so that [`Proxy`] can check its own timeouts while
+    /// waiting for the peer. `None` makes `read()` block.
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> Result<(), Error> {
+        let _ = timeout;
+        Err(Error::Unsupported)
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl<C: Write + ?Sized> Write for Box<C> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        (**self).write(buf)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        (**self).flush()
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl<C: Read + ?Sized> Read for Box<C> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        (**self).read(buf)
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl<C: Connection + ?Sized> Connection for Box<C> {
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> Result<(), Error> {
+        (**self).set_read_timeout(timeout)
+    }
+}
+
+impl<C: Write + ?Sized> Write for &mut C {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        (**self).write(buf)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        (**self).flush()
+    }
+}
+
+impl<C: Read + ?Sized> Read for &mut C {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        (**self).read(buf)
+    }
+}
+
+impl<C: Connection + ?Sized> Connection for &mut C {
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> Result<(), Error> {
+        (**self).set_read_timeout(timeout)
+    }
+}
+
+/// Monotonic clock used by [`Proxy`] to implement timeouts and keep-alive.
+///
+/// Firmware provides its own (e.g. based on the TSC), while
+/// [`std_clock::StdClock`] is available with the `std` feature.
+pub trait Clock {
+    /// Milliseconds elapsed since an arbitrary, fixed point in time.
+    fn now_ms(&self) -> u64;
+}
+
+#[cfg(feature = "std")]
+pub mod std_clock {
+    use std::time::Instant;
+
+    use super::Clock;
+
+    /// [`Clock`] based on [`Instant`].
+    pub struct StdClock(Instant);
+
+    impl StdClock {
+        pub fn new() -> Self {
+            StdClock(Instant::now())
+        }
+    }
+
+    impl Default for StdClock {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl Clock for StdClock {
+        fn now_ms(&self) -> u64 {
+            self.0.elapsed().as_millis().try_into().unwrap_or(u64::MAX)
+        }
+    }
+}
+
+/// Maps errors returned by `std::io` reads, reporting read timeouts as
+/// [`Error::WouldBlock`].
+#[cfg(feature = "std")]
+fn read_error(e: std::io::Error) -> Error {
+    match e.kind() {
+        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut => Error::WouldBlock,
+        _ => Error::ReadError(e.into()),
+    }
+}
+
+#[cfg(feature = "alloc")]
+mod framing;
+
+#[cfg(feature = "heapless")]
+pub mod heapless;
+
+#[cfg(feature = "std")]
+pub mod stdio;
+
+#[cfg(feature = "std")]
+pub mod tcp;
+
+#[cfg(all(feature = "serial", target_os = "linux"))]
+pub mod serial;
+
+#[cfg(all(feature = "vsock", target_os = "linux"))]
+pub mod vsock;
 
 #[cfg(feature = "std")]
 pub mod unix {
     use std::io::{Read as IoRead, Write as IoWrite};
 
-    use anyhow::anyhow;
-
-    use super::{Connection, Error, Read, Write};
+    use super::{read_error, Connection, Error, Read, Write};
+    use crate::lib::Duration;
 
     pub struct UnixConnection(pub std::os::unix::net::UnixStream);
 
     impl Write for UnixConnection {
         fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
-            self.0.write(buf).map_err(|e| Error::WriteError(anyhow!(e)))
+            self.0.write(buf).map_err(|e| Error::WriteError(e.into()))
         }
 
         fn flush(&mut self) -> Result<(), Error> {
-            self.0.flush().map_err(|e| Error::FlushError(anyhow!(e)))
+            self.0.flush().map_err(|e| Error::FlushError(e.into()))
         }
     }
 
     impl Read for UnixConnection {
         fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
-            self.0.read(buf).map_err(|e| Error::ReadError(anyhow!(e)))
+            self.0.read(buf).map_err(read_error)
         }
     }
 
-    impl Connection for UnixConnection {}
+    impl Connection for UnixConnection {
+        fn set_read_timeout(&mut self, timeout: Option<Duration>) -> Result<(), Error> {
+            self.0
+                .set_read_timeout(timeout)
+                .map_err(|e| Error::ReadError(e.into()))
+        }
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Eq, PartialEq)]
 pub enum HttpMethod {
     GET,
     POST,
+    PUT,
+    DELETE,
+    PATCH,
+    HEAD,
+}
+
+impl HttpMethod {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HttpMethod::GET => "GET",
+            HttpMethod::POST => "POST",
+            HttpMethod::PUT => "PUT",
+            HttpMethod::DELETE => "DELETE",
+            HttpMethod::PATCH => "PATCH",
+            HttpMethod::HEAD => "HEAD",
+        }
+    }
+}
+
+impl fmt::Display for HttpMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+pub const HEADER_CONTENT_TYPE: &str = "content-type";
+pub const HEADER_SET_COOKIE: &str = "set-cookie";
+
+/// Response headers that the proxy copies back to the guest.
+pub const RESPONSE_HEADERS: [&str; 2] = [HEADER_CONTENT_TYPE, HEADER_SET_COOKIE];
+
+/// Encoding of the body carried in a [`Request`] or [`Response`].
+///
+/// `Text` bodies are carried as they are, `Base64` bodies contain the base64
+/// encoding of arbitrary binary data (e.g. raw keys or DER certificates).
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Default, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum BodyEncoding {
+    #[default]
+    Text,
+    Base64,
+}
+
+impl BodyEncoding {
+    pub fn is_text(&self) -> bool {
+        *self == BodyEncoding::Text
+    }
 }
 
+/// Request forwarded by the proxy to the remote server.
+///
+/// `headers` and `query` are omitted from the wire format when empty, and
+/// `body` when it is `null`, so the proxy does not attach a body to requests
+/// that don't carry one (e.g. GET). When `encoding` is `Base64`, `body` is a
+/// string and the proxy sends the decoded bytes instead of JSON.
+#[cfg(feature = "alloc")]
 #[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
 pub struct Request {
+    /// Identifier used to match the response, assigned by [`Proxy::send`] if
+    /// not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<u32>,
     pub endpoint: String,
     pub method: HttpMethod,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub query: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Value::is_null")]
     pub body: Value,
+    #[serde(default, skip_serializing_if = "BodyEncoding::is_text")]
+    pub encoding: BodyEncoding,
+}
+
+#[cfg(feature = "alloc")]
+impl Request {
+    pub fn new(endpoint: String, method: HttpMethod, body: Value) -> Self {
+        Request {
+            id: None,
+            endpoint,
+            method,
+            headers: BTreeMap::new(),
+            query: BTreeMap::new(),
+            body,
+            encoding: BodyEncoding::Text,
+        }
+    }
+
+    /// Creates a request with a binary body, carried base64 encoded.
+    pub fn with_bytes(endpoint: String, method: HttpMethod, body: &[u8]) -> Self {
+        Request {
+            encoding: BodyEncoding::Base64,
+            ..Self::new(endpoint, method, Value::String(Base64::encode_string(body)))
+        }
+    }
+
+    pub fn get(endpoint: String) -> Self {
+        Self::new(endpoint, HttpMethod::GET, Value::Null)
+    }
+
+    pub fn post(endpoint: String, body: Value) -> Self {
+        Self::new(endpoint, HttpMethod::POST, body)
+    }
+
+    pub fn put(endpoint: String, body: Value) -> Self {
+        Self::new(endpoint, HttpMethod::PUT, body)
+    }
+
+    pub fn delete(endpoint: String) -> Self {
+        Self::new(endpoint, HttpMethod::DELETE, Value::Null)
+    }
+
+    pub fn patch(endpoint: String, body: Value) -> Self {
+        Self::new(endpoint, HttpMethod::PATCH, body)
+    }
+
+    pub fn head(endpoint: String) -> Self {
+        Self::new(endpoint, HttpMethod::HEAD, Value::Null)
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers
+            .insert(name.to_ascii_lowercase(), value.to_string());
+        self
+    }
+
+    pub fn query(mut self, key: &str, value: &str) -> Self {
+        self.query.insert(key.to_string(), value.to_string());
+        self
+    }
+
+    pub fn content_type(self, value: &str) -> Self {
+        self.header(HEADER_CONTENT_TYPE, value)
+    }
+
+    /// Returns the bytes to send to the remote server: the JSON encoding of
+    /// `body` for `Text` requests, the decoded body for `Base64` ones.
+    pub fn body_bytes(&self) -> Result<Vec<u8>, Error> {
+        match self.encoding {
+            BodyEncoding::Text => Ok(serde_json::to_vec(&self.body)?),
+            BodyEncoding::Base64 => {
+                let body = self.body.as_str().ok_or(base64ct::Error::InvalidEncoding)?;
+                Ok(Base64::decode_vec(body)?)
+            }
+        }
+    }
 }
 
+/// Response returned by the proxy.
+///
+/// `headers` contains only the headers listed in [`RESPONSE_HEADERS`], with
+/// lowercase names and all the values received for each (e.g. one per cookie
+/// for `set-cookie`, which cannot be joined). Bodies that are not valid UTF-8
+/// are carried base64 encoded, as indicated by `encoding`.
+#[cfg(feature = "alloc")]
 #[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
 pub struct Response {
+    /// Identifier of the request this response answers. Peers that do not
+    /// support request IDs leave it unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<u32>,
     pub status: u16,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, Vec<String>>,
     pub body: String,
+    #[serde(default, skip_serializing_if = "BodyEncoding::is_text")]
+    pub encoding: BodyEncoding,
 }
 
+#[cfg(feature = "alloc")]
 impl Response {
+    pub fn new(status: u16, body: String) -> Self {
+        Response {
+            id: None,
+            status,
+            headers: BTreeMap::new(),
+            body,
+            encoding: BodyEncoding::Text,
+        }
+    }
+
+    /// Sets the body, falling back to base64 if `body` is not valid UTF-8.
+    pub fn set_body(&mut self, body: &[u8]) {
+        match core::str::from_utf8(body) {
+            Ok(text) => {
+                self.body = text.to_string();
+                self.encoding = BodyEncoding::Text;
+            }
+            Err(_) => {
+                self.body = Base64::encode_string(body);
+                self.encoding = BodyEncoding::Base64;
+            }
+        }
+    }
+
+    /// Sets the body for a peer with the negotiated `capabilities`.
+    ///
+    /// Like [`Response::set_body`] if the peer supports [`CAP_BINARY_BODY`],
+    /// otherwise the body is always sent as text, with invalid UTF-8 replaced.
+    pub fn set_body_for(&mut self, body: &[u8], capabilities: u32) {
+        if capabilities & CAP_BINARY_BODY != 0 {
+            self.set_body(body);
+        } else {
+            self.body = String::from_utf8_lossy(body).into_owned();
+            self.encoding = BodyEncoding::Text;
+        }
+    }
+
+    /// Returns the raw body, decoding it if it is base64 encoded.
+    pub fn body_bytes(&self) -> Result<Vec<u8>, Error> {
+        match self.encoding {
+            BodyEncoding::Text => Ok(self.body.as_bytes().to_vec()),
+            BodyEncoding::Base64 => Ok(Base64::decode_vec(&self.body)?),
+        }
+    }
+
+    /// Returns the raw body as a secret, decoding it if it is base64 encoded.
+    ///
+    /// The body is zeroed in the response, as is any copy of it made while
+    /// decoding.
+    pub fn into_secret_body(mut self) -> Result<SecretBytes, Error> {
+        let body = mem::take(&mut self.body);
+        match self.encoding {
+            BodyEncoding::Text => Ok(SecretBytes::new(body.into_bytes())),
+            BodyEncoding::Base64 => {
+                let body = SecretBytes::new(body.into_bytes());
+                let mut secret = vec![0u8; body.expose_secret().len().div_ceil(4) * 3];
+                match Base64::decode(body.expose_secret(), &mut secret) {
+                    Ok(decoded) => {
+                        let len = decoded.len();
+                        secret.truncate(len);
+                        Ok(SecretBytes::new(secret))
+                    }
+                    Err(e) => {
+                        secret.zeroize();
+                        Err(e.into())
+                    }
+                }
+            }
+        }
+    }
+
     pub fn is_success(&self) -> bool {
         self.status >= 200 && self.status <= 299
     }
-}
 
-#[derive(Copy, Clone, Debug)]
-pub enum RequestType {
-    Auth,
-    Attest,
-    Key,
+    /// Returns the first value of the header `name`.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.header_values(name).first().map(|v| v.as_str())
+    }
+
+    /// Returns all the values of the header `name`, in the order received.
+    pub fn header_values(&self, name: &str) -> &[String] {
+        self.headers
+            .get(&name.to_ascii_lowercase())
+            .map_or(&[], |v| v.as_slice())
+    }
+
+    /// Adds `value` to the values of the header `name`.
+    pub fn append_header(&mut self, name: &str, value: &str) {
+        self.headers
+            .entry(name.to_ascii_lowercase())
+            .or_default()
+            .push(value.to_string());
+    }
+
+    pub fn content_type(&self) -> Option<&str> {
+        self.header(HEADER_CONTENT_TYPE)
+    }
 }
 
+/// KBS requests of a client, sent through a [`Proxy`].
+///
+/// The bodies are only serialized here, the responses are returned as they
+/// are so that [`ClientSession`](crate::client_session::ClientSession) can
+/// verify them.
+#[cfg(feature = "alloc")]
 pub trait ProxyRequest {
-    fn make(
+    /// Sends the `auth` request, returning the body of the challenge.
+    fn auth<C: Connection>(
+        &self,
+        proxy: &mut Proxy<C>,
+        request: &KbsRequest,
+    ) -> Result<Vec<u8>, Error>;
+    /// Sends the `attest` request.
+    fn attest<C: Connection>(
         &self,
-        proxy: &mut Proxy,
-        req_type: RequestType,
-        body: Option<&Value>,
-    ) -> Result<Option<String>, Error>;
+        proxy: &mut Proxy<C>,
+        attestation: &Attestation,
+    ) -> Result<(), Error>;
+    /// Fetches the secret, returning the body of the response.
+    fn key<C: Connection>(&self, proxy: &mut Proxy<C>) -> Result<SecretBytes, Error>;
+
+    /// Sends the `auth` request of `session`, falling back to the older
+    /// protocol versions of the client while the KBS rejects it with
+    /// [`STATUS_VERSION_REJECTED`], and returns the body of the challenge.
+    ///
+    /// Once no version is left, the last rejection is returned.
+    fn negotiate<C: Connection>(
+        &self,
+        proxy: &mut Proxy<C>,
+        session: &mut ClientSession,
+    ) -> Result<Vec<u8>, crate::Error>
+    where
+        Self: TeeSession + Sized,
+    {
+        loop {
+            let request = session.request(self)?;
+
+            match self.auth(proxy, &request) {
+                Err(e @ Error::HttpError(STATUS_VERSION_REJECTED, _)) => {
+                    if session.fallback(self).is_err() {
+                        return Err(e.into());
+                    }
+                }
+                ret => return Ok(ret?),
+            }
+        }
+    }
 }
 
-pub struct Proxy {
-    conn: Box<dyn Connection>,
+/// HTTP status of the KBS rejecting the protocol version of an `auth`
+/// request.
+pub const STATUS_VERSION_REJECTED: u16 = 401;
+
+/// Magic value at the beginning of each frame.
+pub const FRAME_MAGIC: [u8; 4] = *b"KBCP";
+/// Version of the framing protocol implemented by this crate.
+pub const PROTOCOL_VERSION: u8 = 1;
+/// Size of the header preceding the payload of each frame.
+pub const FRAME_HEADER_SIZE: usize = 12;
+
+/// Default maximum size of a frame payload accepted by [`Proxy`].
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 1 << 20;
+
+/// The frame carries a [`Control`] message instead of a request/response.
+pub const FRAME_FLAG_CONTROL: u8 = 1 << 0;
+
+/// Peer can send and receive base64 encoded bodies (see [`BodyEncoding`]).
+pub const CAP_BINARY_BODY: u32 = 1 << 0;
+/// Capabilities supported by this crate.
+pub const SUPPORTED_CAPABILITIES: u32 = CAP_BINARY_BODY;
+
+/// Header preceding each frame.
+///
+/// All the fields are encoded in little-endian, independently of the host:
+///
+/// | offset | size | field                    |
+/// |--------|------|--------------------------|
+/// | 0      | 4    | magic ([`FRAME_MAGIC`])  |
+/// | 4      | 1    | version                  |
+/// | 5      | 1    | flags (`FRAME_FLAG_*`)   |
+/// | 6      | 2    | reserved, must be 0      |
+/// | 8      | 4    | payload length           |
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct FrameHeader {
+    pub version: u8,
+    pub flags: u8,
+    pub len: u32,
 }
 
-impl Proxy {
-    pub fn new(conn: Box<dyn Connection>) -> Self {
-        Proxy { conn }
+impl FrameHeader {
+    pub fn new(flags: u8, len: u32) -> Self {
+        FrameHeader {
+            version: PROTOCOL_VERSION,
+            flags,
+            len,
+        }
     }
 
-    pub fn write_json(&mut self, json: &Value) -> Result<(), Error> {
-        let buf = serde_json::to_vec(json)?;
+    pub fn to_bytes(&self) -> [u8; FRAME_HEADER_SIZE] {
+        let mut buf = [0u8; FRAME_HEADER_SIZE];
 
-        let len: u32 = buf.len().try_into()?;
-        let buf_len = u32::to_ne_bytes(len);
+        buf[0..4].copy_from_slice(&FRAME_MAGIC);
+        buf[4] = self.version;
+        buf[5] = self.flags;
+        buf[8..12].copy_from_slice(&self.len.to_le_bytes());
 
-        self.write_all(&buf_len)?;
-        self.write_all(&buf)?;
+        buf
+    }
 
-        self.flush()?;
+    pub fn from_bytes(buf: &[u8; FRAME_HEADER_SIZE]) -> Result<Self, Error> {
+        let magic = [buf[0], buf[1], buf[2], buf[3]];
+        if magic != FRAME_MAGIC {
+            return Err(Error::BadMagic(magic));
+        }
 
-        Ok(())
+        let version = buf[4];
+        if version != PROTOCOL_VERSION {
+            return Err(Error::UnsupportedVersion(version));
+        }
+
+        Ok(FrameHeader {
+            version,
+            flags: buf[5],
+            len: u32::from_le_bytes([buf[8], buf[9], buf[10], buf[11]]),
+        })
     }
 
-    pub fn read_json(&mut self) -> Result<Value, Error> {
-        let mut buf_len = [0u8; 4];
+    pub fn is_control(&self) -> bool {
+        self.flags & FRAME_FLAG_CONTROL != 0
+    }
+}
 
-        self.read_exact(&mut buf_len)?;
+/// Messages exchanged between guest and proxy to manage the connection.
+// Deserializing internally tagged enums needs an allocator, see
+// `heapless::ControlFrame` for the heapless mode
+#[derive(Serialize, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "alloc", derive(Deserialize))]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Control {
+    /// First message sent by both peers, carrying the protocol version and
+    /// the `CAP_*` capabilities they support.
+    Hello {
+        version: u8,
+        capabilities: u32,
+    },
+    /// Sent when a corrupted frame is received with [`Framing::Cobs`], asking
+    /// the peer to send its last frame again.
+    Nak,
+    /// Keep-alive request, the peer answers with a `Pong` with the same `seq`.
+    Ping {
+        seq: u32,
+    },
+    Pong {
+        seq: u32,
+    },
+}
 
-        let len: usize = u32::from_ne_bytes(buf_len).try_into()?;
-        let mut buf = vec![0u8; len];
+/// How frames are delimited on the connection.
+///
+/// Both peers must be configured with the same framing.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum Framing {
+    /// Each frame is delimited by the length in its header. Suitable for
+    /// reliable connections (e.g. sockets).
+    #[default]
+    Length,
+    /// Each frame, followed by its CRC-32, is COBS encoded and terminated by a
+    /// zero byte. Corrupted frames are detected and retransmitted, so this is
+    /// suitable for lossy links (e.g. UARTs).
+    Cobs,
+}
 
-        self.read_exact(&mut buf)?;
+/// Default number of consecutive corrupted frames tolerated with
+/// [`Framing::Cobs`].
+pub const DEFAULT_MAX_RETRIES: u32 = 3;
 
-        let json = serde_json::from_slice(&buf)?;
+/// Serializes `msg` as JSON in `buf`, reusing its capacity.
+#[cfg(feature = "std")]
+fn serialize_into<T: Serialize + ?Sized>(
+    buf: &mut Vec<u8>,
+    msg: &T,
+    _max: usize,
+) -> Result<(), Error> {
+    Ok(serde_json::to_writer(buf, msg)?)
+}
 
-        Ok(json)
+/// Serializes `msg` as JSON in `buf`, reusing its capacity.
+///
+/// Without std, serde_json can only serialize into a new vector, so the
+/// message is written by serde-json-core in `buf`, which is grown up to `max`
+/// bytes until the message fits.
+#[cfg(all(feature = "alloc", not(feature = "std")))]
+fn serialize_into<T: Serialize + ?Sized>(
+    buf: &mut Vec<u8>,
+    msg: &T,
+    max: usize,
+) -> Result<(), Error> {
+    let mut len = buf.capacity().clamp(1, max.max(1));
+    loop {
+        buf.resize(len, 0);
+        match serde_json_core::to_slice(msg, buf) {
+            Ok(written) => {
+                buf.truncate(written);
+                return Ok(());
+            }
+            Err(_) if len < max => len = len.saturating_mul(2).min(max),
+            Err(_) => {
+                buf.clear();
+                return Err(Error::FrameTooLarge(len + 1, max));
+            }
+        }
     }
 }
 
-impl Write for Proxy {
-    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
-        self.conn.write(buf)
-    }
+/// Proxy protocol endpoint over a connection of type `C`.
+///
+/// Firmware with a single transport can use the concrete connection type,
+/// avoiding dynamic dispatch; [`BoxedProxy`] can be used when the transport is
+/// chosen at runtime.
+#[cfg(feature = "alloc")]
+pub struct Proxy<C: Connection> {
+    conn: C,
+    capabilities: u32,
+    max_frame_size: usize,
+    // Buffer reused to serialize and receive messages
+    buf: Vec<u8>,
+    framing: Framing,
+    max_retries: u32,
+    // COBS framing: last frame sent, kept for retransmission
+    tx: Vec<u8>,
+    // COBS framing: bytes received but not yet consumed
+    rx: Vec<u8>,
+    next_id: u32,
+    // Requests sent and not yet received, with their response if it arrived
+    // out of order
+    in_flight: BTreeMap<u32, Option<Response>>,
+    clock: Option<Box<dyn Clock + Send>>,
+    timeout_ms: Option<u64>,
+    keepalive_ms: Option<u64>,
+    // Time of the last data received, or of the start of the current wait
+    last_rx_ms: u64,
+    last_ping_ms: u64,
+    ping_seq: u32,
+}
 
-    fn flush(&mut self) -> Result<(), Error> {
-        self.conn.flush()
-    }
+/// Request serialized with the ID assigned by the proxy.
+#[cfg(feature = "alloc")]
+#[derive(Serialize)]
+struct TaggedRequest<'a> {
+    id: u32,
+    #[serde(flatten)]
+    req: &'a Request,
 }
 
-impl Read for Proxy {
-    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
-        self.conn.read(buf)
+/// [`Proxy`] over a connection selected at runtime.
+#[cfg(feature = "alloc")]
+pub type BoxedProxy = Proxy<Box<dyn Connection>>;
+
+#[cfg(feature = "alloc")]
+impl<C: Connection> Proxy<C> {
+    pub fn new(conn: C) -> Self {
+        Proxy {
+            conn,
+            capabilities: 0,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            buf: Vec::new(),
+            framing: Framing::Length,
+            max_retries: DEFAULT_MAX_RETRIES,
+            tx: Vec::new(),
+            rx: Vec::new(),
+            next_id: 1,
+            in_flight: BTreeMap::new(),
+            clock: None,
+            timeout_ms: None,
+            keepalive_ms: None,
+            last_rx_ms: 0,
+            last_ping_ms: 0,
+            ping_seq: 0,
+        }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use serde_json::json;
+    /// Sets the clock used for timeouts and keep-alive.
+    ///
+    /// Timeouts are checked each time a read of the connection returns
+    /// [`Error::WouldBlock`], so the connection must be non-blocking or have a
+    /// read timeout (see [`Connection::set_read_timeout`]).
+    pub fn set_clock<K: Clock + Send + 'static>(&mut self, clock: K) {
+        self.clock = Some(Box::new(clock));
+    }
 
-    use super::*;
+    /// Fails reads with [`Error::Timeout`] if nothing is received from the
+    /// peer for `timeout_ms` milliseconds. Requires a clock.
+    pub fn set_timeout(&mut self, timeout_ms: Option<u64>) {
+        self.timeout_ms = timeout_ms;
+    }
 
-    struct Buffer {
-        vec: Vec<u8>,
+    /// While waiting for the peer with a timeout set, sends a ping every
+    /// `keepalive_ms` milliseconds without data received, so that a live
+    /// peer answers before the timeout expires. Requires a clock.
+    pub fn set_keepalive(&mut self, keepalive_ms: Option<u64>) {
+        self.keepalive_ms = keepalive_ms;
+    }
+
+    /// Sends a ping to the peer.
+    ///
+    /// Pings are answered while the peer reads messages, and the proxy can
+    /// send them while processing a slow request, so the guest does not time
+    /// out. Pings and pongs received are handled transparently by reads.
+    pub fn ping(&mut self) -> Result<(), Error> {
+        self.ping_seq = self.ping_seq.wrapping_add(1);
+        self.write_oob_control(&Control::Ping { seq: self.ping_seq })
+    }
+
+    pub fn framing(&self) -> Framing {
+        self.framing
+    }
+
+    pub fn set_framing(&mut self, framing: Framing) {
+        self.framing = framing;
+    }
+
+    /// Sets how many consecutive corrupted frames are tolerated with
+    /// [`Framing::Cobs`] before failing with [`Error::CorruptFrame`].
+    pub fn set_max_retries(&mut self, max_retries: u32) {
+        self.max_retries = max_retries;
+    }
+
+    pub fn max_frame_size(&self) -> usize {
+        self.max_frame_size
+    }
+
+    /// Sets the maximum size of the payload of frames sent and received.
+    ///
+    /// Frames announcing a bigger payload are rejected with
+    /// [`Error::FrameTooLarge`] before any buffer is allocated. The payload
+    /// is left unread, so the connection should be dropped after that.
+    pub fn set_max_frame_size(&mut self, max_frame_size: usize) {
+        self.max_frame_size = max_frame_size;
+    }
+
+    /// Consumes the proxy, returning the underlying connection.
+    pub fn into_inner(self) -> C {
+        self.conn
+    }
+
+    /// Capabilities agreed with the peer during the handshake.
+    pub fn capabilities(&self) -> u32 {
+        self.capabilities
+    }
+
+    /// Starts the handshake with the proxy (guest side), advertising
+    /// `capabilities`.
+    ///
+    /// Returns the capabilities supported by both peers.
+    pub fn handshake(&mut self, capabilities: u32) -> Result<u32, Error> {
+        self.write_control(&Control::Hello {
+            version: PROTOCOL_VERSION,
+            capabilities,
+        })?;
+
+        let Control::Hello {
+            version,
+            capabilities: remote,
+        } = self.read_control()?
+        else {
+            return Err(Error::UnexpectedFrame);
+        };
+        if version != PROTOCOL_VERSION {
+            return Err(Error::UnsupportedVersion(version));
+        }
+
+        self.capabilities = capabilities & remote;
+        Ok(self.capabilities)
+    }
+
+    /// Waits for the handshake started by the guest (proxy side), replying
+    /// with `capabilities`.
+    ///
+    /// Returns the capabilities supported by both peers.
+    pub fn accept_handshake(&mut self, capabilities: u32) -> Result<u32, Error> {
+        let Control::Hello {
+            version,
+            capabilities: remote,
+        } = self.read_control()?
+        else {
+            return Err(Error::UnexpectedFrame);
+        };
+
+        self.write_control(&Control::Hello {
+            version: PROTOCOL_VERSION,
+            capabilities,
+        })?;
+
+        if version != PROTOCOL_VERSION {
+            return Err(Error::UnsupportedVersion(version));
+        }
+
+        self.capabilities = capabilities & remote;
+        Ok(self.capabilities)
+    }
+
+    fn write_frame(&mut self, flags: u8, payload: &[u8]) -> Result<(), Error> {
+        if payload.len() > self.max_frame_size {
+            return Err(Error::FrameTooLarge(payload.len(), self.max_frame_size));
+        }
+
+        let header = FrameHeader::new(flags, payload.len().try_into()?);
+
+        match self.framing {
+            Framing::Length => {
+                self.write_all(&header.to_bytes())?;
+                self.write_all(payload)?;
+            }
+            Framing::Cobs => {
+                self.tx.clear();
+                encode_cobs(&mut self.tx, &header, payload);
+                self.conn.write_all(&self.tx)?;
+            }
+        }
+
+        self.flush()?;
+
+        Ok(())
+    }
+
+    /// Sends a control frame (e.g. a NAK or a ping) possibly in the middle
+    /// of receiving a frame, without replacing the last frame we sent.
+    fn write_oob_control(&mut self, control: &Control) -> Result<(), Error> {
+        let payload = serde_json::to_vec(control)?;
+        let header = FrameHeader::new(FRAME_FLAG_CONTROL, payload.len().try_into()?);
+
+        match self.framing {
+            Framing::Length => {
+                self.conn.write_all(&header.to_bytes())?;
+                self.conn.write_all(&payload)?;
+            }
+            Framing::Cobs => {
+                let mut frame = Vec::new();
+                encode_cobs(&mut frame, &header, &payload);
+                self.conn.write_all(&frame)?;
+            }
+        }
+
+        self.flush()
+    }
+
+    /// Serializes `msg` in the internal buffer and sends it.
+    fn write_serialized<T: Serialize + ?Sized>(&mut self, flags: u8, msg: &T) -> Result<(), Error> {
+        let mut buf = mem::take(&mut self.buf);
+        buf.clear();
+
+        let ret = serialize_into(&mut buf, msg, self.max_frame_size)
+            .and_then(|_| self.write_frame(flags, &buf));
+
+        self.buf = buf;
+        ret
+    }
+
+    fn read_header(&mut self) -> Result<FrameHeader, Error> {
+        let mut buf_header = [0u8; FRAME_HEADER_SIZE];
+
+        self.read_exact(&mut buf_header)?;
+
+        let header = FrameHeader::from_bytes(&buf_header)?;
+        let len: usize = header.len.try_into()?;
+        if len > self.max_frame_size {
+            return Err(Error::FrameTooLarge(len, self.max_frame_size));
+        }
+
+        Ok(header)
+    }
+
+    /// Reads the payload of the next frame in the internal buffer, handling
+    /// keep-alive frames.
+    fn read_frame(&mut self) -> Result<FrameHeader, Error> {
+        self.start_wait();
+
+        loop {
+            let header = match self.framing {
+                Framing::Length => {
+                    let header = self.read_header()?;
+                    self.read_payload(header.len.try_into()?)?;
+                    header
+                }
+                Framing::Cobs => self.read_cobs_frame()?,
+            };
+
+            if header.is_control() && self.handle_keepalive()? {
+                continue;
+            }
+
+            return Ok(header);
+        }
+    }
+
+    /// Reads `len` bytes of payload in the internal buffer.
+    fn read_payload(&mut self, len: usize) -> Result<(), Error> {
+        let mut buf = mem::take(&mut self.buf);
+        buf.clear();
+        buf.resize(len, 0);
+        let ret = self.read_exact(&mut buf);
+        self.buf = buf;
+        ret
+    }
+
+    /// Answers a ping, or ignores a pong, in the internal buffer.
+    ///
+    /// Returns `false` for any other control frame.
+    fn handle_keepalive(&mut self) -> Result<bool, Error> {
+        match serde_json::from_slice(&self.buf) {
+            Ok(Control::Ping { seq }) => {
+                self.write_oob_control(&Control::Pong { seq })?;
+                Ok(true)
+            }
+            Ok(Control::Pong { .. }) => Ok(true),
+            _ => Ok(false),
+        }
+    }
+
+    fn start_wait(&mut self) {
+        if let Some(clock) = &self.clock {
+            self.last_rx_ms = clock.now_ms();
+            self.last_ping_ms = self.last_rx_ms;
+        }
+    }
+
+    /// Called when a read would block: checks the timeout and sends a ping if
+    /// the peer has been quiet for too long.
+    fn check_timeout(&mut self) -> Result<(), Error> {
+        let (Some(clock), Some(timeout_ms)) = (&self.clock, self.timeout_ms) else {
+            return Err(Error::WouldBlock);
+        };
+
+        let now = clock.now_ms();
+        if now.saturating_sub(self.last_rx_ms) >= timeout_ms {
+            return Err(Error::Timeout);
+        }
+
+        if let Some(keepalive_ms) = self.keepalive_ms {
+            if now.saturating_sub(self.last_ping_ms) >= keepalive_ms {
+                self.last_ping_ms = now;
+                self.ping()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reads the next valid COBS frame, asking for retransmission of corrupted
+    /// ones and retransmitting our last frame when asked by the peer.
+    fn read_cobs_frame(&mut self) -> Result<FrameHeader, Error> {
+        let mut retries = 0;
+
+        loop {
+            let header = match self.recv_cobs() {
+                Ok(header) => header,
+                Err(Error::CorruptFrame) => {
+                    retries += 1;
+                    if retries > self.max_retries {
+                        return Err(Error::CorruptFrame);
+                    }
+                    self.write_oob_control(&Control::Nak)?;
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+
+            if header.is_control() {
+                if let Ok(Control::Nak) = serde_json::from_slice(&self.buf) {
+                    retries += 1;
+                    if retries > self.max_retries {
+                        return Err(Error::CorruptFrame);
+                    }
+                    if !self.tx.is_empty() {
+                        self.conn.write_all(&self.tx)?;
+                        self.flush()?;
+                    }
+                    continue;
+                }
+            }
+
+            return Ok(header);
+        }
+    }
+
+    /// Receives bytes up to the next delimiter and decodes the frame in the
+    /// internal buffer.
+    ///
+    /// Returns [`Error::CorruptFrame`] if the frame is not valid, after
+    /// discarding it, so the next call starts from the following frame.
+    fn recv_cobs(&mut self) -> Result<FrameHeader, Error> {
+        let max_len =
+            framing::max_encoded_len(FRAME_HEADER_SIZE + self.max_frame_size + framing::CRC_SIZE);
+        let mut overflow = false;
+
+        loop {
+            if let Some(pos) = self.rx.iter().position(|&b| b == 0) {
+                if pos == 0 {
+                    // Empty frame (e.g. delimiter sent to resync), skip it
+                    self.rx.drain(..1);
+                    continue;
+                }
+
+                let ret = if overflow {
+                    Err(Error::CorruptFrame)
+                } else {
+                    self.decode_cobs(pos)
+                };
+                self.rx[..pos].zeroize();
+                self.rx.drain(..=pos);
+                return ret;
+            }
+
+            // Too much data without delimiter: discard it, the frame will be
+            // reported as corrupted once the delimiter is found
+            if self.rx.len() > max_len {
+                overflow = true;
+                self.rx.clear();
+            }
+
+            let mut chunk = [0u8; 256];
+            let n = self.read(&mut chunk)?;
+            self.rx.extend_from_slice(&chunk[..n]);
+
+            if n == 0 {
+                if self.rx.is_empty() && !overflow {
+                    return Err(Error::Eof);
+                }
+                return Err(Error::UnexpectedEof);
+            }
+        }
+    }
+
+    fn decode_cobs(&mut self, len: usize) -> Result<FrameHeader, Error> {
+        self.buf.clear();
+        if framing::decode(&self.rx[..len], &mut self.buf).is_none() {
+            return Err(Error::CorruptFrame);
+        }
+
+        let Some(data_len) = self
+            .buf
+            .len()
+            .checked_sub(FRAME_HEADER_SIZE + framing::CRC_SIZE)
+        else {
+            return Err(Error::CorruptFrame);
+        };
+
+        let (data, crc) = self.buf.split_at(FRAME_HEADER_SIZE + data_len);
+        if Crc32::checksum(data).to_le_bytes() != crc {
+            return Err(Error::CorruptFrame);
+        }
+
+        let mut buf_header = [0u8; FRAME_HEADER_SIZE];
+        buf_header.copy_from_slice(&data[..FRAME_HEADER_SIZE]);
+        let header = FrameHeader::from_bytes(&buf_header)?;
+
+        let len: usize = header.len.try_into()?;
+        if len != data_len {
+            return Err(Error::CorruptFrame);
+        }
+        if len > self.max_frame_size {
+            return Err(Error::FrameTooLarge(len, self.max_frame_size));
+        }
+
+        self.buf.truncate(FRAME_HEADER_SIZE + data_len);
+        self.buf.drain(..FRAME_HEADER_SIZE);
+
+        Ok(header)
+    }
+
+    /// Reserves capacity for at least `capacity` bytes in the internal
+    /// buffer, so that messages up to that size are sent and received without
+    /// further allocations.
+    pub fn reserve_buffer(&mut self, capacity: usize) {
+        self.buf.reserve(capacity.saturating_sub(self.buf.len()));
+    }
+
+    /// Sends `payload`, which must already contain a serialized message,
+    /// without any intermediate copy.
+    pub fn write_raw(&mut self, payload: &[u8]) -> Result<(), Error> {
+        self.write_frame(0, payload)
+    }
+
+    /// Reads the payload of the next message into `buf`, returning its size.
+    ///
+    /// If the payload doesn't fit in `buf`, it is discarded and
+    /// [`Error::FrameTooLarge`] is returned, so the next message can still be
+    /// read.
+    pub fn read_into(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        if self.framing == Framing::Cobs {
+            // The frame must be decoded and checked before it can be copied
+            let header = self.read_frame()?;
+            if header.is_control() {
+                return Err(Error::UnexpectedFrame);
+            }
+
+            let len = self.buf.len();
+            if len > buf.len() {
+                return Err(Error::FrameTooLarge(len, buf.len()));
+            }
+            buf[..len].copy_from_slice(&self.buf);
+            return Ok(len);
+        }
+
+        self.start_wait();
+        let header = loop {
+            let header = self.read_header()?;
+            if !header.is_control() {
+                break header;
+            }
+
+            self.read_payload(header.len.try_into()?)?;
+            if !self.handle_keepalive()? {
+                return Err(Error::UnexpectedFrame);
+            }
+        };
+        let len: usize = header.len.try_into()?;
+
+        let Some(payload) = buf.get_mut(..len) else {
+            // Bounded by the maximum frame size, checked by read_header()
+            self.read_payload(len)?;
+            self.buf.zeroize();
+            return Err(Error::FrameTooLarge(len, buf.len()));
+        };
+        self.read_exact(payload)?;
+
+        Ok(len)
+    }
+
+    /// Serializes `msg` into the internal buffer, reused across calls, and
+    /// sends it.
+    pub fn write_message<T: Serialize + ?Sized>(&mut self, msg: &T) -> Result<(), Error> {
+        self.write_serialized(0, msg)
+    }
+
+    /// Reads the next message into the internal buffer, reused across calls,
+    /// and deserializes it straight into `T`.
+    ///
+    /// The buffer is zeroed afterwards, since the message may carry a secret.
+    pub fn read_message<T: DeserializeOwned>(&mut self) -> Result<T, Error> {
+        let header = self.read_frame()?;
+        if header.is_control() {
+            return Err(Error::UnexpectedFrame);
+        }
+
+        let ret = serde_json::from_slice(&self.buf);
+        self.buf.zeroize();
+
+        Ok(ret?)
+    }
+
+    pub fn write_control(&mut self, control: &Control) -> Result<(), Error> {
+        self.write_serialized(FRAME_FLAG_CONTROL, control)
+    }
+
+    pub fn read_control(&mut self) -> Result<Control, Error> {
+        let header = self.read_frame()?;
+        if !header.is_control() {
+            return Err(Error::UnexpectedFrame);
+        }
+
+        Ok(serde_json::from_slice(&self.buf)?)
+    }
+
+    pub fn write_json(&mut self, json: &Value) -> Result<(), Error> {
+        self.write_message(json)
+    }
+
+    /// Sends `req` without waiting for its response, which can then be
+    /// received with [`Proxy::receive`].
+    ///
+    /// Returns the ID of the request, which is `req.id` if set, otherwise one
+    /// assigned by the proxy.
+    pub fn send(&mut self, req: &Request) -> Result<u32, Error> {
+        let id = match req.id {
+            Some(id) => id,
+            None => {
+                while self.in_flight.contains_key(&self.next_id) {
+                    self.next_id = self.next_id.wrapping_add(1);
+                }
+                self.next_id
+            }
+        };
+        if self.in_flight.contains_key(&id) {
+            return Err(Error::DuplicateRequestId(id));
+        }
+
+        match req.id {
+            Some(_) => self.write_message(req)?,
+            None => self.write_message(&TaggedRequest { id, req })?,
+        }
+
+        self.next_id = id.wrapping_add(1);
+        self.in_flight.insert(id, None);
+
+        Ok(id)
+    }
+
+    /// Waits for the response to the request `id`.
+    ///
+    /// Responses to other requests in flight are kept until they are
+    /// received, while late or duplicated responses are discarded. A
+    /// response without ID comes from a peer that answers in order, so it is
+    /// taken as the response to `id`.
+    pub fn receive(&mut self, id: u32) -> Result<Response, Error> {
+        match self.in_flight.get_mut(&id) {
+            None => return Err(Error::UnknownRequestId(id)),
+            Some(resp) => {
+                if let Some(resp) = resp.take() {
+                    self.in_flight.remove(&id);
+                    return Ok(resp);
+                }
+            }
+        }
+
+        loop {
+            let resp: Response = self.read_message()?;
+
+            match resp.id {
+                None => {}
+                Some(resp_id) if resp_id == id => {}
+                Some(resp_id) => {
+                    if let Some(pending @ None) = self.in_flight.get_mut(&resp_id) {
+                        *pending = Some(resp);
+                    }
+                    continue;
+                }
+            }
+
+            self.in_flight.remove(&id);
+            return Ok(resp);
+        }
+    }
+
+    /// Number of requests sent and not yet received.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    /// Sends `req` to the proxy and waits for the matching response.
+    pub fn request(&mut self, req: &Request) -> Result<Response, Error> {
+        let id = self.send(req)?;
+        self.receive(id)
+    }
+
+    pub fn read_json(&mut self) -> Result<Value, Error> {
+        self.read_message()
+    }
+}
+
+/// Appends to `out` the COBS encoding of a frame, followed by its CRC-32 and
+/// by the delimiter.
+#[cfg(feature = "alloc")]
+fn encode_cobs(out: &mut Vec<u8>, header: &FrameHeader, payload: &[u8]) {
+    let header = header.to_bytes();
+
+    let mut crc = Crc32::new();
+    crc.update(&header);
+    crc.update(payload);
+
+    let mut enc = framing::Encoder::new(out);
+    enc.extend(&header);
+    enc.extend(payload);
+    enc.extend(&crc.finalize().to_le_bytes());
+    enc.finish();
+}
+
+#[cfg(feature = "alloc")]
+impl<C: Connection> Write for Proxy<C> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        self.conn.write(buf)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.conn.flush()
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl<C: Connection> Read for Proxy<C> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        loop {
+            match self.conn.read(buf) {
+                Err(Error::WouldBlock) => self.check_timeout()?,
+                Ok(n) => {
+                    if let Some(clock) = &self.clock {
+                        self.last_rx_ms = clock.now_ms();
+                    }
+                    return Ok(n);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::thread::{self, JoinHandle};
+
+    use serde_json::json;
+
+    use super::*;
+
+    /// Spawns the proxy side of a test, which completes the handshake over the
+    /// connection returned by `connect` and then runs `serve`.
+    pub(crate) fn spawn_peer<C, F, S>(connect: F, serve: S) -> JoinHandle<()>
+    where
+        C: Connection + 'static,
+        F: FnOnce() -> C + Send + 'static,
+        S: FnOnce(&mut Proxy<C>) + Send + 'static,
+    {
+        thread::spawn(move || {
+            let mut proxy = Proxy::new(connect());
+            proxy.accept_handshake(SUPPORTED_CAPABILITIES).unwrap();
+            serve(&mut proxy);
+        })
+    }
+
+    /// Answers each request with a response carrying the request in its body,
+    /// until the guest disconnects.
+    pub(crate) fn echo<C: Connection>(proxy: &mut Proxy<C>) {
+        while let Ok(req) = proxy.read_message::<Request>() {
+            let mut resp = Response::new(200, serde_json::to_string(&req).unwrap());
+            resp.id = req.id;
+            proxy.write_message(&resp).unwrap();
+        }
+    }
+
+    /// Completes the handshake over `conn` and checks that `req` is sent back
+    /// by [`echo`].
+    pub(crate) fn check_echo<C: Connection>(conn: C, req: Request) {
+        let mut proxy = Proxy::new(conn);
+        proxy.handshake(SUPPORTED_CAPABILITIES).unwrap();
+
+        let id = proxy.send(&req).unwrap();
+        let resp = proxy.receive(id).unwrap();
+        let echoed: Request = serde_json::from_str(&resp.body).unwrap();
+        assert_eq!(
+            echoed,
+            Request {
+                id: Some(id),
+                ..req
+            }
+        );
+    }
+
+    struct Buffer {
+        vec: Vec<u8>,
     }
 
     impl Write for Buffer {
@@ -258,7 +1816,7 @@ mod tests {
         fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
             let len = std::cmp::min(buf.len(), self.vec.len());
             let data = self.vec.drain(0..len);
-            buf[..].clone_from_slice(data.as_slice());
+            buf[..len].clone_from_slice(data.as_slice());
             Ok(len)
         }
     }
@@ -268,13 +1826,13 @@ mod tests {
     #[test]
     fn test_proxy() {
         let conn = Buffer { vec: Vec::new() };
-        let mut proxy = Proxy::new(Box::new(conn));
+        let mut proxy: BoxedProxy = Proxy::new(Box::new(conn));
 
-        let req = Request {
-            endpoint: "/test".to_string(),
-            method: HttpMethod::GET,
-            body: json!("body".to_string()),
-        };
+        let req = Request::new(
+            "/test".to_string(),
+            HttpMethod::GET,
+            json!("body".to_string()),
+        );
 
         proxy.write_json(&json!(req)).unwrap();
         let data = proxy.read_json().unwrap();
@@ -282,4 +1840,518 @@ mod tests {
 
         assert_eq!(req, req2);
     }
+
+    #[test]
+    fn test_proxy_generic_connection() {
+        struct Client;
+
+        impl ProxyRequest for Client {
+            fn auth<C: Connection>(
+                &self,
+                _proxy: &mut Proxy<C>,
+                _request: &KbsRequest,
+            ) -> Result<Vec<u8>, Error> {
+                Err(Error::Unsupported)
+            }
+
+            fn attest<C: Connection>(
+                &self,
+                _proxy: &mut Proxy<C>,
+                _attestation: &Attestation,
+            ) -> Result<(), Error> {
+                Err(Error::Unsupported)
+            }
+
+            fn key<C: Connection>(&self, proxy: &mut Proxy<C>) -> Result<SecretBytes, Error> {
+                proxy.write_raw(b"\"ping\"")?;
+                let mut buf = [0u8; 16];
+                let len = proxy.read_into(&mut buf)?;
+                Ok(SecretBytes::new(buf[..len].to_vec()))
+            }
+        }
+
+        // Borrowed connection, no boxing required
+        let mut conn = Buffer { vec: Vec::new() };
+        let mut proxy = Proxy::new(&mut conn);
+        let data = Client.key(&mut proxy).unwrap();
+        assert_eq!(data.expose_secret(), b"\"ping\"");
+    }
+
+    #[test]
+    fn test_request_headers_query() {
+        let req = Request::new("/test".to_string(), HttpMethod::GET, Value::Null)
+            .header("Authorization", "Bearer token")
+            .query("id", "42");
+
+        assert_eq!(
+            json!(req),
+            json!({
+                "endpoint": "/test",
+                "method": "GET",
+                "headers": {"authorization": "Bearer token"},
+                "query": {"id": "42"},
+            }),
+        );
+
+        // Optional fields can be omitted by the peer
+        let req: Request = serde_json::from_value(json!({
+            "endpoint": "/test",
+            "method": "POST",
+        }))
+        .unwrap();
+        assert!(req.headers.is_empty());
+        assert!(req.query.is_empty());
+        assert_eq!(req.body, Value::Null);
+
+        let resp: Response = serde_json::from_value(json!({
+            "status": 200,
+            "headers": {"content-type": ["application/json"]},
+            "body": "{}",
+        }))
+        .unwrap();
+        assert_eq!(resp.content_type(), Some("application/json"));
+        assert_eq!(resp.header("Set-Cookie"), None);
+        assert!(resp.header_values("Set-Cookie").is_empty());
+
+        // Every cookie is kept
+        let mut resp = Response::new(200, String::new());
+        resp.append_header("Set-Cookie", "session=1; Path=/");
+        resp.append_header("set-cookie", "lang=en");
+        assert_eq!(
+            json!(resp)["headers"],
+            json!({"set-cookie": ["session=1; Path=/", "lang=en"]})
+        );
+        let resp: Response = serde_json::from_value(json!(resp)).unwrap();
+        assert_eq!(resp.header("set-cookie"), Some("session=1; Path=/"));
+        assert_eq!(
+            resp.header_values("set-cookie"),
+            ["session=1; Path=/", "lang=en"]
+        );
+    }
+
+    #[test]
+    fn test_binary_body() {
+        let conn = Buffer { vec: Vec::new() };
+        let mut proxy = Proxy::new(conn);
+
+        let text = "{\"key\": \"value\"}".as_bytes();
+        let mut resp = Response::new(200, String::new());
+        resp.set_body(text);
+        assert_eq!(resp.encoding, BodyEncoding::Text);
+        assert!(json!(resp).get("encoding").is_none());
+        assert_eq!(resp.body_bytes().unwrap(), text);
+
+        let binary = [0x30, 0x82, 0xff, 0x00, 0xfe, 0x80];
+        resp.set_body(&binary);
+        assert_eq!(resp.encoding, BodyEncoding::Base64);
+
+        proxy.write_json(&json!(resp)).unwrap();
+        let data = proxy.read_json().unwrap();
+        assert_eq!(data["encoding"], "base64");
+        let resp2: Response = serde_json::from_value(data).unwrap();
+        assert_eq!(resp2.body_bytes().unwrap(), binary);
+        assert_eq!(resp2.into_secret_body().unwrap().expose_secret(), &binary);
+        resp.set_body(text);
+        assert_eq!(resp.into_secret_body().unwrap().expose_secret(), text);
+
+        // A peer without CAP_BINARY_BODY only gets text
+        let mut resp = Response::new(200, String::new());
+        resp.set_body_for(&binary, 0);
+        assert_eq!(resp.encoding, BodyEncoding::Text);
+        assert_eq!(resp.body, "0\u{fffd}\u{fffd}\0\u{fffd}\u{fffd}");
+        resp.set_body_for(&binary, CAP_BINARY_BODY);
+        assert_eq!(resp.encoding, BodyEncoding::Base64);
+
+        let req = Request::with_bytes("/upload".to_string(), HttpMethod::POST, &binary);
+        proxy.write_json(&json!(req)).unwrap();
+        let req2: Request = serde_json::from_value(proxy.read_json().unwrap()).unwrap();
+        assert_eq!(req2.body_bytes().unwrap(), binary);
+
+        let req = Request::post("/json".to_string(), json!({"a": 1}));
+        assert_eq!(req.body_bytes().unwrap(), b"{\"a\":1}");
+    }
+
+    #[test]
+    fn test_frame_header() {
+        let header = FrameHeader::new(FRAME_FLAG_CONTROL, 0x01020304);
+        let buf = header.to_bytes();
+        assert_eq!(buf, *b"KBCP\x01\x01\x00\x00\x04\x03\x02\x01");
+        assert_eq!(FrameHeader::from_bytes(&buf).unwrap(), header);
+
+        let mut bad = buf;
+        bad[0] = b'X';
+        assert!(matches!(
+            FrameHeader::from_bytes(&bad),
+            Err(Error::BadMagic(m)) if m == *b"XBCP"
+        ));
+
+        let mut bad = buf;
+        bad[4] = PROTOCOL_VERSION + 1;
+        assert!(matches!(
+            FrameHeader::from_bytes(&bad),
+            Err(Error::UnsupportedVersion(v)) if v == PROTOCOL_VERSION + 1
+        ));
+
+        // Peer using the legacy framing (only native-endian length)
+        let mut legacy = 42u32.to_ne_bytes().to_vec();
+        legacy.extend_from_slice(&[b'{'; 41]);
+        legacy.push(b'}');
+        let mut proxy = Proxy::new(Buffer { vec: legacy });
+        assert!(matches!(proxy.read_json(), Err(Error::BadMagic(_))));
+    }
+
+    #[test]
+    fn test_max_frame_size() {
+        // The peer announces a 4 GiB payload, which must not be allocated
+        let header = FrameHeader::new(0, u32::MAX);
+        let conn = Buffer {
+            vec: header.to_bytes().to_vec(),
+        };
+        let mut proxy = Proxy::new(conn);
+        assert!(matches!(
+            proxy.read_json(),
+            Err(Error::FrameTooLarge(len, DEFAULT_MAX_FRAME_SIZE)) if len == u32::MAX as usize
+        ));
+
+        let conn = Buffer { vec: Vec::new() };
+        let mut proxy = Proxy::new(conn);
+        proxy.set_max_frame_size(16);
+        assert_eq!(proxy.max_frame_size(), 16);
+
+        proxy.write_json(&json!("short")).unwrap();
+        assert_eq!(proxy.read_json().unwrap(), json!("short"));
+
+        assert!(matches!(
+            proxy.write_json(&json!("this is too long")),
+            Err(Error::FrameTooLarge(18, 16))
+        ));
+    }
+
+    #[test]
+    fn test_buffer_reuse() {
+        let conn = Buffer { vec: Vec::new() };
+        let mut proxy = Proxy::new(conn);
+        proxy.reserve_buffer(256);
+        let capacity = proxy.buf.capacity();
+
+        for i in 0..8 {
+            let req = Request::get(format!("/test/{i}"));
+            proxy.write_message(&req).unwrap();
+            let req2: Request = proxy.read_message().unwrap();
+            assert_eq!(req, req2);
+        }
+        assert_eq!(proxy.buf.capacity(), capacity);
+
+        // Caller-provided buffers
+        let payload = br#"{"status":200,"body":"ok"}"#;
+        proxy.write_raw(payload).unwrap();
+        let mut buf = [0u8; 64];
+        let len = proxy.read_into(&mut buf).unwrap();
+        assert_eq!(&buf[..len], payload);
+        let resp: Response = serde_json::from_slice(&buf[..len]).unwrap();
+        assert_eq!(resp.body, "ok");
+
+        // An oversized payload is discarded, keeping the stream in sync
+        proxy.write_raw(payload).unwrap();
+        proxy.write_raw(b"next").unwrap();
+        let mut small = [0u8; 8];
+        assert!(matches!(
+            proxy.read_into(&mut small),
+            Err(Error::FrameTooLarge(len, 8)) if len == payload.len()
+        ));
+        assert_eq!(proxy.read_into(&mut small).unwrap(), 4);
+        assert_eq!(&small[..4], b"next");
+    }
+
+    #[test]
+    fn test_handshake() {
+        let (guest, host) = std::os::unix::net::UnixStream::pair().unwrap();
+
+        let host = std::thread::spawn(move || {
+            let mut proxy = Proxy::new(unix::UnixConnection(host));
+            let caps = proxy.accept_handshake(SUPPORTED_CAPABILITIES).unwrap();
+
+            let req: Request = serde_json::from_value(proxy.read_json().unwrap()).unwrap();
+            let resp = Response::new(200, req.endpoint);
+            proxy.write_json(&json!(resp)).unwrap();
+
+            caps
+        });
+
+        let mut proxy = Proxy::new(unix::UnixConnection(guest));
+        let caps = proxy.handshake(CAP_BINARY_BODY | 1 << 31).unwrap();
+        assert_eq!(caps, CAP_BINARY_BODY);
+        assert_eq!(proxy.capabilities(), CAP_BINARY_BODY);
+
+        let resp = proxy.request(&Request::get("/test".to_string())).unwrap();
+        assert_eq!(resp.body, "/test");
+
+        assert_eq!(host.join().unwrap(), CAP_BINARY_BODY);
+    }
+
+    #[test]
+    fn test_handshake_unexpected_frame() {
+        let conn = Buffer { vec: Vec::new() };
+        let mut proxy = Proxy::new(conn);
+
+        // A guest that skips the handshake is rejected by the proxy
+        proxy
+            .write_json(&json!(Request::get("/test".to_string())))
+            .unwrap();
+        assert!(matches!(
+            proxy.accept_handshake(SUPPORTED_CAPABILITIES),
+            Err(Error::UnexpectedFrame)
+        ));
+    }
+
+    #[test]
+    fn test_http_methods() {
+        let body = json!({"data": "value"});
+        let requests = [
+            (Request::get("/r".to_string()), "GET", false),
+            (Request::post("/r".to_string(), body.clone()), "POST", true),
+            (Request::put("/r".to_string(), body.clone()), "PUT", true),
+            (Request::delete("/r".to_string()), "DELETE", false),
+            (
+                Request::patch("/r".to_string(), body.clone()),
+                "PATCH",
+                true,
+            ),
+            (Request::head("/r".to_string()), "HEAD", false),
+        ];
+
+        for (req, method, has_body) in requests {
+            assert_eq!(req.method.as_str(), method);
+
+            let data = json!(req);
+            assert_eq!(data["method"], method);
+            assert_eq!(data.get("body").is_some(), has_body);
+
+            let req2: Request = serde_json::from_value(data).unwrap();
+            assert_eq!(req, req2);
+        }
+    }
+
+    fn cobs_proxy() -> Proxy<Buffer> {
+        let mut proxy = Proxy::new(Buffer { vec: Vec::new() });
+        proxy.set_framing(Framing::Cobs);
+        proxy
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_io_error() {
+        let e = IoError::from(std::io::Error::from_raw_os_error(32));
+        assert_eq!(e.kind(), ErrorKind::BrokenPipe);
+        assert_eq!(e.os_error(), Some(32));
+        assert_eq!(
+            std::io::Error::from(e).kind(),
+            std::io::ErrorKind::BrokenPipe
+        );
+
+        let e = IoError::from(std::io::Error::other("custom"));
+        assert_eq!(e, IoError::new(ErrorKind::Other));
+        assert_eq!(e.to_string(), "other error");
+
+        let e = Error::ReadError(IoError::from_os_error(ErrorKind::ConnectionReset, 104));
+        assert_eq!(
+            e.to_string(),
+            "Read failed - connection reset (os error 104)"
+        );
+    }
+
+    #[test]
+    fn test_cobs_framing() {
+        let mut proxy = cobs_proxy();
+        let req = Request::get("/test".to_string());
+
+        proxy.write_message(&req).unwrap();
+        // The delimiter only appears at the end of the frame
+        let frame = &proxy.conn.vec;
+        assert_eq!(frame.iter().position(|&b| b == 0), Some(frame.len() - 1));
+
+        let req2: Request = proxy.read_message().unwrap();
+        assert_eq!(req, req2);
+        assert!(matches!(proxy.read_json(), Err(Error::Eof)));
+
+        proxy.write_raw(b"raw").unwrap();
+        let mut buf = [0u8; 8];
+        assert_eq!(proxy.read_into(&mut buf).unwrap(), 3);
+        assert_eq!(&buf[..3], b"raw");
+    }
+
+    #[test]
+    fn test_cobs_retransmission() {
+        let req = Request::get("/test".to_string());
+
+        // Corrupted byte: the NAK is looped back, so the frame is sent again
+        let mut proxy = cobs_proxy();
+        proxy.write_message(&req).unwrap();
+        proxy.conn.vec[5] ^= 0x40;
+        let req2: Request = proxy.read_message().unwrap();
+        assert_eq!(req, req2);
+        assert!(proxy.conn.vec.is_empty());
+
+        // Dropped byte
+        let mut proxy = cobs_proxy();
+        proxy.write_message(&req).unwrap();
+        proxy.conn.vec.remove(7);
+        let req2: Request = proxy.read_message().unwrap();
+        assert_eq!(req, req2);
+
+        // Garbage before the frame is discarded at the first delimiter
+        let mut proxy = cobs_proxy();
+        proxy.conn.vec.extend_from_slice(b"garbage\0");
+        proxy.write_message(&req).unwrap();
+        let req2: Request = proxy.read_message().unwrap();
+        assert_eq!(req, req2);
+
+        // Too many corrupted frames
+        let mut proxy = cobs_proxy();
+        proxy.set_max_retries(0);
+        proxy.write_message(&req).unwrap();
+        proxy.conn.vec[5] ^= 0x40;
+        assert!(matches!(
+            proxy.read_message::<Request>(),
+            Err(Error::CorruptFrame)
+        ));
+    }
+
+    #[test]
+    fn test_request_ids() {
+        let mut proxy = Proxy::new(Buffer { vec: Vec::new() });
+
+        let id1 = proxy.send(&Request::get("/one".to_string())).unwrap();
+        let id2 = proxy.send(&Request::get("/two".to_string())).unwrap();
+        assert_ne!(id1, id2);
+        assert_eq!(proxy.in_flight(), 2);
+
+        // The requests are looped back: check the IDs sent on the wire
+        let req: Request = proxy.read_message().unwrap();
+        assert_eq!((req.id, req.endpoint.as_str()), (Some(id1), "/one"));
+        let req: Request = proxy.read_message().unwrap();
+        assert_eq!((req.id, req.endpoint.as_str()), (Some(id2), "/two"));
+
+        let mut req = Request::get("/three".to_string());
+        req.id = Some(id1);
+        assert!(matches!(
+            proxy.send(&req),
+            Err(Error::DuplicateRequestId(id)) if id == id1
+        ));
+
+        // Responses delivered out of order, with a late duplicate
+        let mut resp1 = Response::new(200, "one".to_string());
+        resp1.id = Some(id1);
+        let mut resp2 = Response::new(200, "two".to_string());
+        resp2.id = Some(id2);
+        proxy.write_message(&resp2).unwrap();
+        proxy.write_message(&resp1).unwrap();
+        proxy.write_message(&resp2).unwrap();
+
+        assert_eq!(proxy.receive(id1).unwrap().body, "one");
+        assert_eq!(proxy.in_flight(), 1);
+        assert_eq!(proxy.receive(id2).unwrap().body, "two");
+        assert_eq!(proxy.in_flight(), 0);
+        assert!(matches!(
+            proxy.receive(id2),
+            Err(Error::UnknownRequestId(id)) if id == id2
+        ));
+
+        // The duplicate is discarded while waiting for the next response
+        let duplicate = mem::take(&mut proxy.conn.vec);
+        let id3 = proxy.send(&req).unwrap();
+        assert_eq!(id3, id1);
+        proxy.conn.vec = duplicate;
+        proxy
+            .write_message(&Response::new(204, String::new()))
+            .unwrap();
+        assert_eq!(proxy.receive(id3).unwrap().status, 204);
+    }
+
+    // Reads fail with WouldBlock when no data is available
+    pub(crate) struct Pipe {
+        pub(crate) rx: Vec<u8>,
+        pub(crate) tx: Vec<u8>,
+    }
+
+    impl Write for Pipe {
+        fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+            self.tx.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    impl Read for Pipe {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+            if self.rx.is_empty() {
+                return Err(Error::WouldBlock);
+            }
+            let len = std::cmp::min(buf.len(), self.rx.len());
+            buf[..len].copy_from_slice(&self.rx[..len]);
+            self.rx.drain(..len);
+            Ok(len)
+        }
+    }
+
+    impl Connection for Pipe {}
+
+    // Advances by 10ms each time it is read
+    pub(crate) struct TestClock(pub(crate) std::cell::Cell<u64>);
+
+    impl Clock for TestClock {
+        fn now_ms(&self) -> u64 {
+            self.0.set(self.0.get() + 10);
+            self.0.get()
+        }
+    }
+
+    // Decodes the control frames in `data`, which are not returned by reads
+    pub(crate) fn controls(mut data: &[u8]) -> Vec<Control> {
+        let mut controls = Vec::new();
+        while !data.is_empty() {
+            let (header, rest) = data.split_at(FRAME_HEADER_SIZE);
+            let header = FrameHeader::from_bytes(header.try_into().unwrap()).unwrap();
+            let (payload, rest) = rest.split_at(header.len as usize);
+            controls.push(serde_json::from_slice(payload).unwrap());
+            data = rest;
+        }
+        controls
+    }
+
+    #[test]
+    fn test_keepalive() {
+        let mut peer = Proxy::new(Buffer { vec: Vec::new() });
+        peer.write_control(&Control::Ping { seq: 7 }).unwrap();
+        peer.write_message(&json!("data")).unwrap();
+
+        let mut proxy = Proxy::new(Pipe {
+            rx: peer.into_inner().vec,
+            tx: Vec::new(),
+        });
+        proxy.set_clock(TestClock(0.into()));
+        proxy.set_timeout(Some(100));
+        proxy.set_keepalive(Some(30));
+
+        // The ping is answered while waiting for the message
+        assert_eq!(proxy.read_json().unwrap(), json!("data"));
+        assert_eq!(
+            controls(&mem::take(&mut proxy.conn.tx)),
+            [Control::Pong { seq: 7 }]
+        );
+
+        // A silent peer is pinged, then times out
+        assert!(matches!(proxy.read_json(), Err(Error::Timeout)));
+        let pings = controls(&mem::take(&mut proxy.conn.tx));
+        assert_eq!(
+            pings[..2],
+            [Control::Ping { seq: 1 }, Control::Ping { seq: 2 }]
+        );
+
+        // Without a timeout, the caller sees WouldBlock
+        proxy.set_timeout(None);
+        assert!(matches!(proxy.read_json(), Err(Error::WouldBlock)));
+    }
 }
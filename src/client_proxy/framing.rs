@@ -0,0 +1,203 @@
+//! Helpers for the COBS framing (see [`super::Framing::Cobs`]).
+//!
+//! COBS (Consistent Overhead Byte Stuffing) encodes data without any zero
+//! byte, so that a zero can be used as frame delimiter: after a corrupted or
+//! truncated frame, the receiver resynchronises on the next delimiter.
+
+use crate::lib::Vec;
+
+/// Size of the CRC-32 appended to each frame.
+pub const CRC_SIZE: usize = 4;
+
+const CRC32_TABLE: [u32; 256] = crc32_table();
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+
+    table
+}
+
+/// Incremental CRC-32 (IEEE 802.3).
+#[derive(Copy, Clone, Debug)]
+pub struct Crc32(u32);
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Crc32 {
+    pub fn new() -> Self {
+        Crc32(0xffff_ffff)
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for &b in data {
+            self.0 = CRC32_TABLE[((self.0 ^ b as u32) & 0xff) as usize] ^ (self.0 >> 8);
+        }
+    }
+
+    pub fn finalize(self) -> u32 {
+        !self.0
+    }
+
+    pub fn checksum(data: &[u8]) -> u32 {
+        let mut crc = Self::new();
+        crc.update(data);
+        crc.finalize()
+    }
+}
+
+/// Maximum size of the COBS encoding of `len` bytes, without delimiter.
+pub fn max_encoded_len(len: usize) -> usize {
+    len + len / 254 + 1
+}
+
+/// Streaming COBS encoder, appending the encoded data to a `Vec`.
+pub struct Encoder<'a> {
+    out: &'a mut Vec<u8>,
+    // Position of the code byte of the current block
+    code_pos: usize,
+}
+
+impl<'a> Encoder<'a> {
+    pub fn new(out: &'a mut Vec<u8>) -> Self {
+        let code_pos = out.len();
+        out.push(1);
+        Encoder { out, code_pos }
+    }
+
+    pub fn push(&mut self, b: u8) {
+        if b == 0 {
+            self.next_block();
+            return;
+        }
+
+        self.out.push(b);
+        self.out[self.code_pos] += 1;
+        if self.out[self.code_pos] == 0xff {
+            self.next_block();
+        }
+    }
+
+    pub fn extend(&mut self, data: &[u8]) {
+        for &b in data {
+            self.push(b);
+        }
+    }
+
+    fn next_block(&mut self) {
+        self.code_pos = self.out.len();
+        self.out.push(1);
+    }
+
+    /// Terminates the encoding, appending the zero delimiter.
+    pub fn finish(self) {
+        self.out.push(0);
+    }
+}
+
+/// Decodes `src`, which must not contain the delimiter, appending the result
+/// to `dst`.
+///
+/// Returns `None` if `src` is not a valid COBS encoding.
+pub fn decode(src: &[u8], dst: &mut Vec<u8>) -> Option<()> {
+    let mut i = 0;
+
+    while i < src.len() {
+        let code = src[i] as usize;
+        if code == 0 || i + code > src.len() {
+            return None;
+        }
+
+        dst.extend_from_slice(&src[i + 1..i + code]);
+        i += code;
+
+        if code < 0xff && i < src.len() {
+            dst.push(0);
+        }
+    }
+
+    Some(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut enc = Encoder::new(&mut out);
+        enc.extend(data);
+        enc.finish();
+        out
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(Crc32::checksum(b""), 0);
+        assert_eq!(Crc32::checksum(b"123456789"), 0xcbf4_3926);
+
+        let mut crc = Crc32::new();
+        crc.update(b"1234");
+        crc.update(b"56789");
+        assert_eq!(crc.finalize(), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn test_cobs() {
+        let vectors: [(&[u8], &[u8]); 5] = [
+            (&[], &[0x01, 0x00]),
+            (&[0x00], &[0x01, 0x01, 0x00]),
+            (&[0x00, 0x00], &[0x01, 0x01, 0x01, 0x00]),
+            (
+                &[0x11, 0x22, 0x00, 0x33],
+                &[0x03, 0x11, 0x22, 0x02, 0x33, 0x00],
+            ),
+            (
+                &[0x11, 0x00, 0x00, 0x00],
+                &[0x02, 0x11, 0x01, 0x01, 0x01, 0x00],
+            ),
+        ];
+
+        for (data, encoded) in vectors {
+            assert_eq!(encode(data), encoded);
+
+            let mut decoded = Vec::new();
+            decode(&encoded[..encoded.len() - 1], &mut decoded).unwrap();
+            assert_eq!(decoded, data);
+        }
+
+        // Blocks longer than 254 bytes
+        for len in [253, 254, 255, 508, 1000] {
+            let data: Vec<u8> = (0..len).map(|i| (i % 255 + 1) as u8).collect();
+            let encoded = encode(&data);
+            assert!(!encoded[..encoded.len() - 1].contains(&0));
+            assert!(encoded.len() - 1 <= max_encoded_len(len));
+
+            let mut decoded = Vec::new();
+            decode(&encoded[..encoded.len() - 1], &mut decoded).unwrap();
+            assert_eq!(decoded, data);
+        }
+
+        // Code byte pointing past the end
+        assert!(decode(&[0x05, 0x11], &mut Vec::new()).is_none());
+    }
+}
@@ -0,0 +1,650 @@
+//! Proxy protocol without a memory allocator.
+//!
+//! [`Proxy`] serializes messages with serde-json-core into a buffer of `N`
+//! bytes it owns, so the maximum size of a message is fixed at compile time.
+//! Requests borrow their fields, while the body of responses is copied into a
+//! fixed-capacity string.
+//!
+//! The wire format is the same as [`super::Proxy`], but only
+//! [`Framing::Length`](super::Framing::Length) is supported and responses are
+//! expected in order. Pings from the peer are answered while waiting for a
+//! response.
+//!
+//! Timeouts and keep-alive work as with [`super::Proxy`], except that the
+//! clock is a type parameter `K` of the proxy. Without a clock (the default
+//! [`NoClock`]), reads of the connection should block.
+
+use ::heapless::String;
+use base64ct::{Base64, Encoding};
+use serde::{ser::SerializeMap, Deserialize, Serialize, Serializer};
+use zeroize::Zeroize;
+
+use super::{
+    BodyEncoding, Clock, Connection, Control, Error, FrameHeader, HttpMethod, Read,
+    FRAME_FLAG_CONTROL, FRAME_HEADER_SIZE, PROTOCOL_VERSION, STATUS_VERSION_REJECTED,
+};
+use crate::client_session::heapless::{
+    Attestation, ClientSession, Request as KbsRequest, TeeSession,
+};
+
+fn serialize_pairs<S: Serializer>(
+    pairs: &&[(&str, &str)],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    let mut map = serializer.serialize_map(Some(pairs.len()))?;
+    for (name, value) in pairs.iter() {
+        map.serialize_entry(name, value)?;
+    }
+    map.end()
+}
+
+/// Request forwarded by the proxy to the remote server, see
+/// [`super::Request`].
+///
+/// `body` is serialized as it is, so it can be any type implementing
+/// `Serialize`. Header names must be lowercase.
+#[derive(Serialize, Debug)]
+pub struct Request<'a, B: Serialize = ()> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<u32>,
+    pub endpoint: &'a str,
+    pub method: HttpMethod,
+    #[serde(
+        skip_serializing_if = "<[_]>::is_empty",
+        serialize_with = "serialize_pairs"
+    )]
+    pub headers: &'a [(&'a str, &'a str)],
+    #[serde(
+        skip_serializing_if = "<[_]>::is_empty",
+        serialize_with = "serialize_pairs"
+    )]
+    pub query: &'a [(&'a str, &'a str)],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub body: Option<&'a B>,
+}
+
+impl<'a, B: Serialize> Request<'a, B> {
+    pub fn new(endpoint: &'a str, method: HttpMethod, body: Option<&'a B>) -> Self {
+        Request {
+            id: None,
+            endpoint,
+            method,
+            headers: &[],
+            query: &[],
+            body,
+        }
+    }
+
+    pub fn post(endpoint: &'a str, body: &'a B) -> Self {
+        Self::new(endpoint, HttpMethod::POST, Some(body))
+    }
+
+    pub fn put(endpoint: &'a str, body: &'a B) -> Self {
+        Self::new(endpoint, HttpMethod::PUT, Some(body))
+    }
+
+    pub fn patch(endpoint: &'a str, body: &'a B) -> Self {
+        Self::new(endpoint, HttpMethod::PATCH, Some(body))
+    }
+
+    pub fn headers(mut self, headers: &'a [(&'a str, &'a str)]) -> Self {
+        self.headers = headers;
+        self
+    }
+
+    pub fn query(mut self, query: &'a [(&'a str, &'a str)]) -> Self {
+        self.query = query;
+        self
+    }
+}
+
+impl<'a> Request<'a> {
+    pub fn get(endpoint: &'a str) -> Self {
+        Self::new(endpoint, HttpMethod::GET, None)
+    }
+
+    pub fn delete(endpoint: &'a str) -> Self {
+        Self::new(endpoint, HttpMethod::DELETE, None)
+    }
+
+    pub fn head(endpoint: &'a str) -> Self {
+        Self::new(endpoint, HttpMethod::HEAD, None)
+    }
+}
+
+/// Response returned by the proxy, with a body of up to `N` bytes.
+///
+/// The headers are not kept.
+#[derive(Deserialize, Debug)]
+pub struct Response<const N: usize> {
+    #[serde(default)]
+    pub id: Option<u32>,
+    pub status: u16,
+    pub body: String<N>,
+    #[serde(default)]
+    pub encoding: BodyEncoding,
+}
+
+impl<const N: usize> Response<N> {
+    pub fn is_success(&self) -> bool {
+        self.status >= 200 && self.status <= 299
+    }
+
+    /// Copies the raw body into `out`, decoding it if it is base64 encoded,
+    /// and returns its length.
+    pub fn body_into(&self, out: &mut [u8]) -> Result<usize, Error> {
+        match self.encoding {
+            BodyEncoding::Text => {
+                let body = self.body.as_bytes();
+                let out_len = out.len();
+                out.get_mut(..body.len())
+                    .ok_or(Error::BufferTooSmall(body.len(), out_len))?
+                    .copy_from_slice(body);
+                Ok(body.len())
+            }
+            BodyEncoding::Base64 => {
+                let max = self.body.len().div_ceil(4) * 3;
+                let out_len = out.len();
+                match Base64::decode(&self.body, out) {
+                    Ok(decoded) => Ok(decoded.len()),
+                    Err(base64ct::Error::InvalidLength) if out_len < max => {
+                        Err(Error::BufferTooSmall(max, out_len))
+                    }
+                    Err(e) => Err(e.into()),
+                }
+            }
+        }
+    }
+
+    /// Like [`Response::body_into`], for a body carrying a secret, which is
+    /// zeroed in the response afterwards.
+    pub fn secret_body_into(mut self, out: &mut [u8]) -> Result<usize, Error> {
+        let ret = self.body_into(out);
+        self.body.as_mut_str().zeroize();
+
+        ret
+    }
+}
+
+/// Control message as received, since [`Control`] can only be deserialized
+/// with an allocator.
+#[derive(Deserialize)]
+struct ControlFrame<'a> {
+    #[serde(rename = "type")]
+    kind: &'a str,
+    version: Option<u8>,
+    capabilities: Option<u32>,
+    seq: Option<u32>,
+}
+
+impl ControlFrame<'_> {
+    fn into_control(self) -> Result<Control, Error> {
+        match (self.kind, self.version, self.capabilities, self.seq) {
+            ("hello", Some(version), Some(capabilities), _) => Ok(Control::Hello {
+                version,
+                capabilities,
+            }),
+            ("nak", ..) => Ok(Control::Nak),
+            ("ping", _, _, Some(seq)) => Ok(Control::Ping { seq }),
+            ("pong", _, _, Some(seq)) => Ok(Control::Pong { seq }),
+            _ => Err(Error::UnexpectedFrame),
+        }
+    }
+}
+
+/// [`Clock`] of a [`Proxy`] without timeouts, which cannot be set.
+pub enum NoClock {}
+
+impl Clock for NoClock {
+    fn now_ms(&self) -> u64 {
+        match *self {}
+    }
+}
+
+/// Maximum size of the pings sent by [`Link`].
+const PING_SIZE: usize = 64;
+
+/// Connection of a [`Proxy`] with the state of its timeouts, kept apart from
+/// the buffers so that reads can send pings while filling them.
+struct Link<C, K> {
+    conn: C,
+    clock: Option<K>,
+    timeout_ms: Option<u64>,
+    keepalive_ms: Option<u64>,
+    // Time of the last data received, or of the start of the current wait
+    last_rx_ms: u64,
+    last_ping_ms: u64,
+    ping_seq: u32,
+}
+
+impl<C: Connection, K: Clock> Link<C, K> {
+    fn ping(&mut self) -> Result<(), Error> {
+        self.ping_seq = self.ping_seq.wrapping_add(1);
+
+        let mut buf = [0u8; PING_SIZE];
+        let len = serde_json_core::to_slice(&Control::Ping { seq: self.ping_seq }, &mut buf)?;
+        let header = FrameHeader::new(FRAME_FLAG_CONTROL, len.try_into()?);
+
+        self.conn.write_all(&header.to_bytes())?;
+        self.conn.write_all(&buf[..len])?;
+        self.conn.flush()
+    }
+
+    fn start_wait(&mut self) {
+        if let Some(clock) = &self.clock {
+            self.last_rx_ms = clock.now_ms();
+            self.last_ping_ms = self.last_rx_ms;
+        }
+    }
+
+    /// Called when a read would block: checks the timeout and sends a ping if
+    /// the peer has been quiet for too long.
+    fn check_timeout(&mut self) -> Result<(), Error> {
+        let (Some(clock), Some(timeout_ms)) = (&self.clock, self.timeout_ms) else {
+            return Err(Error::WouldBlock);
+        };
+
+        let now = clock.now_ms();
+        if now.saturating_sub(self.last_rx_ms) >= timeout_ms {
+            return Err(Error::Timeout);
+        }
+
+        if let Some(keepalive_ms) = self.keepalive_ms {
+            if now.saturating_sub(self.last_ping_ms) >= keepalive_ms {
+                self.last_ping_ms = now;
+                self.ping()?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<C: Connection, K: Clock> Read for Link<C, K> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        loop {
+            match self.conn.read(buf) {
+                Err(Error::WouldBlock) => self.check_timeout()?,
+                Ok(n) => {
+                    if let Some(clock) = &self.clock {
+                        self.last_rx_ms = clock.now_ms();
+                    }
+                    return Ok(n);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// Proxy protocol endpoint over a connection of type `C`, sending and
+/// receiving messages of up to `N` bytes, with timeouts measured by `K`.
+pub struct Proxy<C: Connection, const N: usize, K: Clock = NoClock> {
+    link: Link<C, K>,
+    capabilities: u32,
+    // Buffer used to serialize and receive messages
+    buf: [u8; N],
+    // Buffer used to unescape the strings of received messages
+    scratch: [u8; N],
+    next_id: u32,
+}
+
+impl<C: Connection, const N: usize, K: Clock> Proxy<C, N, K> {
+    pub fn new(conn: C) -> Self {
+        Proxy {
+            link: Link {
+                conn,
+                clock: None,
+                timeout_ms: None,
+                keepalive_ms: None,
+                last_rx_ms: 0,
+                last_ping_ms: 0,
+                ping_seq: 0,
+            },
+            capabilities: 0,
+            buf: [0; N],
+            scratch: [0; N],
+            next_id: 1,
+        }
+    }
+
+    /// Sets the clock used for timeouts and keep-alive, see
+    /// [`super::Proxy::set_clock`].
+    pub fn set_clock(&mut self, clock: K) {
+        self.link.clock = Some(clock);
+    }
+
+    /// Fails reads with [`Error::Timeout`] if nothing is received from the
+    /// peer for `timeout_ms` milliseconds. Requires a clock.
+    pub fn set_timeout(&mut self, timeout_ms: Option<u64>) {
+        self.link.timeout_ms = timeout_ms;
+    }
+
+    /// While waiting for the peer with a timeout set, sends a ping every
+    /// `keepalive_ms` milliseconds without data received. Requires a clock.
+    pub fn set_keepalive(&mut self, keepalive_ms: Option<u64>) {
+        self.link.keepalive_ms = keepalive_ms;
+    }
+
+    /// Sends a ping to the peer.
+    pub fn ping(&mut self) -> Result<(), Error> {
+        self.link.ping()
+    }
+
+    /// Consumes the proxy, returning the underlying connection.
+    pub fn into_inner(self) -> C {
+        self.link.conn
+    }
+
+    /// Capabilities agreed with the peer during the handshake.
+    pub fn capabilities(&self) -> u32 {
+        self.capabilities
+    }
+
+    /// Starts the handshake with the proxy, advertising `capabilities`.
+    ///
+    /// Returns the capabilities supported by both peers.
+    pub fn handshake(&mut self, capabilities: u32) -> Result<u32, Error> {
+        self.write_control(&Control::Hello {
+            version: PROTOCOL_VERSION,
+            capabilities,
+        })?;
+
+        let Control::Hello {
+            version,
+            capabilities: remote,
+        } = self.read_control()?
+        else {
+            return Err(Error::UnexpectedFrame);
+        };
+        if version != PROTOCOL_VERSION {
+            return Err(Error::UnsupportedVersion(version));
+        }
+
+        self.capabilities = capabilities & remote;
+        Ok(self.capabilities)
+    }
+
+    fn write_serialized<T: Serialize + ?Sized>(&mut self, flags: u8, msg: &T) -> Result<(), Error> {
+        let len = serde_json_core::to_slice(msg, &mut self.buf)?;
+        let header = FrameHeader::new(flags, len.try_into()?);
+
+        self.link.conn.write_all(&header.to_bytes())?;
+        self.link.conn.write_all(&self.buf[..len])?;
+        self.link.conn.flush()
+    }
+
+    /// Reads the payload of the next frame in the internal buffer, answering
+    /// pings and ignoring pongs, and returns its header.
+    fn read_frame(&mut self) -> Result<(FrameHeader, usize), Error> {
+        self.link.start_wait();
+
+        loop {
+            let mut buf_header = [0u8; FRAME_HEADER_SIZE];
+            self.link.read_exact(&mut buf_header)?;
+
+            let header = FrameHeader::from_bytes(&buf_header)?;
+            let len: usize = header.len.try_into()?;
+            if len > N {
+                return Err(Error::FrameTooLarge(len, N));
+            }
+            self.link.read_exact(&mut self.buf[..len])?;
+
+            if header.is_control() {
+                let (frame, _) = serde_json_core::from_slice::<ControlFrame>(&self.buf[..len])?;
+                match frame.into_control() {
+                    Ok(Control::Ping { seq }) => {
+                        self.write_control(&Control::Pong { seq })?;
+                        continue;
+                    }
+                    Ok(Control::Pong { .. }) => continue,
+                    _ => {}
+                }
+            }
+
+            return Ok((header, len));
+        }
+    }
+
+    /// Serializes `msg` into the internal buffer and sends it.
+    pub fn write_message<T: Serialize + ?Sized>(&mut self, msg: &T) -> Result<(), Error> {
+        self.write_serialized(0, msg)
+    }
+
+    pub fn write_control(&mut self, control: &Control) -> Result<(), Error> {
+        self.write_serialized(FRAME_FLAG_CONTROL, control)
+    }
+
+    pub fn read_control(&mut self) -> Result<Control, Error> {
+        let (header, len) = self.read_frame()?;
+        if !header.is_control() {
+            return Err(Error::UnexpectedFrame);
+        }
+
+        let (frame, _) = serde_json_core::from_slice::<ControlFrame>(&self.buf[..len])?;
+        frame.into_control()
+    }
+
+    /// Sends `req` without waiting for its response, returning its ID.
+    pub fn send<B: Serialize>(&mut self, req: &Request<B>) -> Result<u32, Error> {
+        let id = req.id.unwrap_or(self.next_id);
+
+        self.write_message(&Request {
+            id: Some(id),
+            ..*req
+        })?;
+        self.next_id = id.wrapping_add(1);
+
+        Ok(id)
+    }
+
+    /// Waits for the response to the request `id`, discarding responses to
+    /// previous requests.
+    pub fn receive(&mut self, id: u32) -> Result<Response<N>, Error> {
+        loop {
+            let (header, len) = self.read_frame()?;
+            if header.is_control() {
+                return Err(Error::UnexpectedFrame);
+            }
+
+            // The response may carry a secret, zero its copies once parsed
+            let ret = serde_json_core::from_slice_escaped::<Response<N>>(
+                &self.buf[..len],
+                &mut self.scratch,
+            );
+            self.buf[..len].zeroize();
+            self.scratch.zeroize();
+            let (resp, _) = ret?;
+
+            match resp.id {
+                Some(resp_id) if resp_id != id => continue,
+                _ => return Ok(resp),
+            }
+        }
+    }
+
+    pub fn request<B: Serialize>(&mut self, req: &Request<B>) -> Result<Response<N>, Error> {
+        let id = self.send(req)?;
+        self.receive(id)
+    }
+}
+
+/// Allocation-free counterpart of [`super::ProxyRequest`].
+///
+/// The bodies of the responses are copied into `out`, returning their length.
+pub trait ProxyRequest {
+    /// Sends the `auth` request, returning the body of the challenge.
+    fn auth<C: Connection, const N: usize, K: Clock, P: Serialize>(
+        &self,
+        proxy: &mut Proxy<C, N, K>,
+        request: &KbsRequest<P>,
+        out: &mut [u8],
+    ) -> Result<usize, Error>;
+    /// Sends the `attest` request.
+    fn attest<C: Connection, const N: usize, K: Clock, E: Serialize>(
+        &self,
+        proxy: &mut Proxy<C, N, K>,
+        attestation: &Attestation<E>,
+    ) -> Result<(), Error>;
+    /// Fetches the secret, returning the body of the response.
+    fn key<C: Connection, const N: usize, K: Clock>(
+        &self,
+        proxy: &mut Proxy<C, N, K>,
+        out: &mut [u8],
+    ) -> Result<usize, Error>;
+
+    /// Sends the `auth` request of `session`, falling back to the older
+    /// protocol versions of the client while the KBS rejects it with
+    /// [`STATUS_VERSION_REJECTED`], and returns the length of the challenge.
+    ///
+    /// Once no version is left, the last rejection is returned.
+    fn negotiate<C: Connection, const N: usize, K: Clock, const M: usize>(
+        &self,
+        proxy: &mut Proxy<C, N, K>,
+        session: &mut ClientSession<M>,
+        out: &mut [u8],
+    ) -> Result<usize, crate::Error>
+    where
+        Self: TeeSession + Sized,
+    {
+        loop {
+            let ret = {
+                let request = session.request(self)?;
+                self.auth(proxy, &request, out)
+            };
+
+            match ret {
+                Err(e @ Error::HttpStatus(STATUS_VERSION_REJECTED)) => {
+                    if session.fallback(self).is_err() {
+                        return Err(e.into());
+                    }
+                }
+                ret => return Ok(ret?),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::net::UnixStream;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::client_proxy::{
+        tests::{controls, spawn_peer, Pipe, TestClock},
+        unix::UnixConnection,
+        Proxy as AllocProxy, Request as AllocRequest, Response as AllocResponse,
+        SUPPORTED_CAPABILITIES,
+    };
+
+    #[derive(Serialize)]
+    struct Body<'a> {
+        nonce: &'a str,
+    }
+
+    #[test]
+    fn test_heapless_proxy() {
+        let (guest, host) = UnixStream::pair().unwrap();
+
+        let host = spawn_peer(
+            move || UnixConnection(host),
+            move |proxy| {
+                let req: AllocRequest = proxy.read_message().unwrap();
+                assert_eq!(req.endpoint, "/kbs/v0/auth");
+                assert_eq!(req.method, HttpMethod::POST);
+                assert_eq!(req.headers.get("content-type").unwrap(), "application/json");
+                assert_eq!(req.body, json!({"nonce": "42"}));
+
+                // A late response to another request is discarded
+                proxy
+                    .write_message(&AllocResponse {
+                        id: Some(100),
+                        ..AllocResponse::new(500, "late".to_string())
+                    })
+                    .unwrap();
+                proxy.ping().unwrap();
+
+                let mut resp = AllocResponse::new(200, json!({"nonce": "42"}).to_string());
+                resp.append_header("content-type", "application/json");
+                resp.id = req.id;
+                proxy.write_message(&resp).unwrap();
+
+                // The pong sent by the guest is skipped
+
+                let req: AllocRequest = proxy.read_message().unwrap();
+                assert_eq!(req.endpoint, "/kbs/v0/resource");
+                assert!(req.body.is_null());
+                let mut resp = AllocResponse::new(200, "".to_string());
+                resp.set_body(&[0xff, 0x00, 0x01]);
+                resp.id = req.id;
+                proxy.write_message(&resp).unwrap();
+            },
+        );
+
+        let mut proxy = Proxy::<_, 512>::new(UnixConnection(guest));
+        assert_eq!(
+            proxy.handshake(SUPPORTED_CAPABILITIES).unwrap(),
+            SUPPORTED_CAPABILITIES
+        );
+
+        let body = Body { nonce: "42" };
+        let req =
+            Request::post("/kbs/v0/auth", &body).headers(&[("content-type", "application/json")]);
+        let resp = proxy.request(&req).unwrap();
+        assert!(resp.is_success());
+        assert_eq!(resp.body.as_str(), r#"{"nonce":"42"}"#);
+
+        let resp = proxy.request(&Request::get("/kbs/v0/resource")).unwrap();
+        assert_eq!(resp.encoding, BodyEncoding::Base64);
+        let mut out = [0u8; 3];
+        assert_eq!(resp.body_into(&mut out).unwrap(), 3);
+        assert_eq!(out, [0xff, 0x00, 0x01]);
+        assert!(matches!(
+            resp.body_into(&mut [0u8; 2]),
+            Err(Error::BufferTooSmall(3, 2))
+        ));
+
+        host.join().unwrap();
+    }
+
+    #[test]
+    fn test_heapless_keepalive() {
+        let mut peer = AllocProxy::new(Pipe {
+            rx: Vec::new(),
+            tx: Vec::new(),
+        });
+        peer.write_control(&Control::Ping { seq: 7 }).unwrap();
+        peer.write_message(&AllocResponse::new(200, "data".to_string()))
+            .unwrap();
+
+        let mut proxy = Proxy::<_, 256, _>::new(Pipe {
+            rx: peer.into_inner().tx,
+            tx: Vec::new(),
+        });
+        proxy.set_clock(TestClock(0.into()));
+        proxy.set_timeout(Some(100));
+        proxy.set_keepalive(Some(30));
+
+        // The ping is answered while waiting for the response
+        assert_eq!(proxy.receive(1).unwrap().body.as_str(), "data");
+        let pipe = &mut proxy.link.conn;
+        assert_eq!(
+            controls(&std::mem::take(&mut pipe.tx)),
+            [Control::Pong { seq: 7 }]
+        );
+
+        // A silent peer is pinged, then times out
+        assert!(matches!(proxy.receive(2), Err(Error::Timeout)));
+        let pings = controls(&std::mem::take(&mut proxy.link.conn.tx));
+        assert_eq!(
+            pings[..2],
+            [Control::Ping { seq: 1 }, Control::Ping { seq: 2 }]
+        );
+
+        // Without a timeout, the caller sees WouldBlock
+        proxy.set_timeout(None);
+        assert!(matches!(proxy.receive(2), Err(Error::WouldBlock)));
+    }
+}
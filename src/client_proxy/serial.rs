@@ -0,0 +1,341 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Read as IoRead, Write as IoWrite},
+    mem,
+    os::{fd::AsRawFd, unix::fs::OpenOptionsExt},
+    path::Path,
+    str::FromStr,
+    time::Duration,
+};
+
+use super::{read_error, Connection, Error, Read, Write};
+
+/// Flow control used on the serial line.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum FlowControl {
+    #[default]
+    None,
+    /// RTS/CTS
+    Hardware,
+    /// XON/XOFF
+    Software,
+}
+
+impl FromStr for FlowControl {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(FlowControl::None),
+            "hardware" => Ok(FlowControl::Hardware),
+            "software" => Ok(FlowControl::Software),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "flow control must be one of: none, hardware, software",
+            )),
+        }
+    }
+}
+
+/// Line settings applied to the tty by [`SerialConnection::configure`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct SerialConfig {
+    pub baud_rate: u32,
+    pub flow_control: FlowControl,
+}
+
+impl Default for SerialConfig {
+    fn default() -> Self {
+        SerialConfig {
+            baud_rate: 115200,
+            flow_control: FlowControl::None,
+        }
+    }
+}
+
+fn speed(baud_rate: u32) -> io::Result<libc::speed_t> {
+    let speed = match baud_rate {
+        9600 => libc::B9600,
+        19200 => libc::B19200,
+        38400 => libc::B38400,
+        57600 => libc::B57600,
+        115200 => libc::B115200,
+        230400 => libc::B230400,
+        460800 => libc::B460800,
+        500000 => libc::B500000,
+        576000 => libc::B576000,
+        921600 => libc::B921600,
+        1000000 => libc::B1000000,
+        1152000 => libc::B1152000,
+        1500000 => libc::B1500000,
+        2000000 => libc::B2000000,
+        2500000 => libc::B2500000,
+        3000000 => libc::B3000000,
+        3500000 => libc::B3500000,
+        4000000 => libc::B4000000,
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unsupported baud rate {baud_rate}"),
+            ))
+        }
+    };
+
+    Ok(speed)
+}
+
+fn cvt(ret: libc::c_int) -> io::Result<()> {
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// Connection over a serial port (tty device).
+///
+/// Serial drivers can return fewer bytes than requested on both read and
+/// write, which is handled by [`Read::read_exact`] and [`Write::write_all`].
+pub struct SerialConnection {
+    file: File,
+    // Reads time out through VTIME, see set_read_timeout()
+    timeout: bool,
+}
+
+impl SerialConnection {
+    /// Wraps the tty `file`, which is expected to be configured already (see
+    /// [`SerialConnection::configure`]).
+    pub fn new(file: File) -> Self {
+        SerialConnection {
+            file,
+            timeout: false,
+        }
+    }
+
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    /// Opens the tty at `path` and configures it with `config`.
+    pub fn open<P: AsRef<Path>>(path: P, config: &SerialConfig) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY)
+            .open(path)?;
+
+        let mut conn = SerialConnection::new(file);
+        conn.configure(config)?;
+
+        Ok(conn)
+    }
+
+    /// Puts the tty in raw mode (no echo, no line editing, no character
+    /// translation), sets the baud rate and the flow control, then discards
+    /// any stale data.
+    ///
+    /// Reads block until data is available, as if the read timeout was unset.
+    pub fn configure(&mut self, config: &SerialConfig) -> io::Result<()> {
+        let fd = self.file.as_raw_fd();
+        let speed = speed(config.baud_rate)?;
+
+        // SAFETY: termios is a plain C struct, all zeroes is a valid value
+        let mut tio: libc::termios = unsafe { mem::zeroed() };
+        // SAFETY: `tio` is a valid termios for the whole call
+        cvt(unsafe { libc::tcgetattr(fd, &mut tio) })?;
+
+        // SAFETY: `tio` is a valid termios, initialized by tcgetattr()
+        unsafe { libc::cfmakeraw(&mut tio) };
+        // SAFETY: `tio` is a valid termios
+        cvt(unsafe { libc::cfsetispeed(&mut tio, speed) })?;
+        // SAFETY: `tio` is a valid termios
+        cvt(unsafe { libc::cfsetospeed(&mut tio, speed) })?;
+
+        // Ignore modem control lines and enable the receiver
+        tio.c_cflag |= libc::CLOCAL | libc::CREAD;
+        tio.c_cflag &= !libc::CRTSCTS;
+        tio.c_iflag &= !(libc::IXON | libc::IXOFF | libc::IXANY);
+        match config.flow_control {
+            FlowControl::None => {}
+            FlowControl::Hardware => tio.c_cflag |= libc::CRTSCTS,
+            FlowControl::Software => tio.c_iflag |= libc::IXON | libc::IXOFF,
+        }
+
+        // Block until at least one byte is available
+        tio.c_cc[libc::VMIN] = 1;
+        tio.c_cc[libc::VTIME] = 0;
+
+        // SAFETY: `tio` is a valid termios for the whole call
+        cvt(unsafe { libc::tcsetattr(fd, libc::TCSANOW, &tio) })?;
+        self.timeout = false;
+        // SAFETY: plain call on a valid descriptor
+        cvt(unsafe { libc::tcflush(fd, libc::TCIOFLUSH) })
+    }
+}
+
+impl Write for SerialConnection {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        loop {
+            match self.file.write(buf) {
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                ret => return ret.map_err(|e| Error::WriteError(e.into())),
+            }
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        // Data written is queued by the tty layer and transmitted anyway, no
+        // need to wait for it with tcdrain()
+        self.file.flush().map_err(|e| Error::FlushError(e.into()))
+    }
+}
+
+impl Read for SerialConnection {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        loop {
+            match self.file.read(buf) {
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                // A pseudo-terminal returns EIO once the other side is closed
+                Err(e) if e.raw_os_error() == Some(libc::EIO) => return Ok(0),
+                // With a timeout, a tty returns 0 bytes when VTIME expires,
+                // see set_read_timeout(), otherwise it is the end of file
+                Ok(0) if self.timeout && !buf.is_empty() => return Err(Error::WouldBlock),
+                ret => return ret.map_err(read_error),
+            }
+        }
+    }
+}
+
+impl Connection for SerialConnection {
+    /// The timeout is rounded up to tenths of a second, up to 25.5 seconds,
+    /// as supported by the VTIME setting of the tty.
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> Result<(), Error> {
+        let fd = self.file.as_raw_fd();
+
+        // SAFETY: termios is a plain C struct, all zeroes is a valid value
+        let mut tio: libc::termios = unsafe { mem::zeroed() };
+        // SAFETY: `tio` is a valid termios for the whole call
+        cvt(unsafe { libc::tcgetattr(fd, &mut tio) }).map_err(|e| Error::ReadError(e.into()))?;
+
+        match timeout {
+            Some(timeout) => {
+                let tenths = timeout.as_millis().div_ceil(100).clamp(1, 255);
+                tio.c_cc[libc::VMIN] = 0;
+                tio.c_cc[libc::VTIME] = tenths as libc::cc_t;
+            }
+            None => {
+                tio.c_cc[libc::VMIN] = 1;
+                tio.c_cc[libc::VTIME] = 0;
+            }
+        }
+
+        // SAFETY: `tio` is a valid termios for the whole call
+        cvt(unsafe { libc::tcsetattr(fd, libc::TCSANOW, &tio) })
+            .map_err(|e| Error::ReadError(e.into()))?;
+        self.timeout = timeout.is_some();
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{os::fd::FromRawFd, ptr};
+
+    use super::*;
+    use crate::client_proxy::{
+        tests::{check_echo, echo, spawn_peer},
+        Request,
+    };
+
+    fn openpty() -> (File, File) {
+        let mut master = 0;
+        let mut slave = 0;
+
+        // SAFETY: `master` and `slave` are valid for writes, the other
+        // parameters are optional
+        let ret = unsafe {
+            libc::openpty(
+                &mut master,
+                &mut slave,
+                ptr::null_mut(),
+                ptr::null(),
+                ptr::null(),
+            )
+        };
+        assert_eq!(ret, 0, "openpty failed: {}", io::Error::last_os_error());
+
+        // SAFETY: both descriptors were just returned by openpty()
+        unsafe { (File::from_raw_fd(master), File::from_raw_fd(slave)) }
+    }
+
+    #[test]
+    fn test_serial_configure() {
+        let (_master, slave) = openpty();
+        let mut conn = SerialConnection::new(slave);
+
+        let config = SerialConfig {
+            baud_rate: 921600,
+            flow_control: FlowControl::Hardware,
+        };
+        conn.configure(&config).unwrap();
+
+        // SAFETY: termios is a plain C struct, all zeroes is a valid value
+        let mut tio: libc::termios = unsafe { mem::zeroed() };
+        // SAFETY: `tio` is a valid termios for the whole call
+        cvt(unsafe { libc::tcgetattr(conn.file().as_raw_fd(), &mut tio) }).unwrap();
+
+        // SAFETY: `tio` is a valid termios
+        assert_eq!(unsafe { libc::cfgetospeed(&tio) }, libc::B921600);
+        assert_ne!(tio.c_cflag & libc::CRTSCTS, 0);
+        assert_eq!(tio.c_lflag & (libc::ICANON | libc::ECHO), 0);
+
+        assert!(conn
+            .configure(&SerialConfig {
+                baud_rate: 12345,
+                ..Default::default()
+            })
+            .is_err());
+        assert_eq!(
+            "software".parse::<FlowControl>().unwrap(),
+            FlowControl::Software
+        );
+    }
+
+    #[test]
+    fn test_serial_pty() {
+        let (master, slave) = openpty();
+
+        let mut guest = SerialConnection::new(slave);
+        guest.configure(&SerialConfig::default()).unwrap();
+
+        // Closing the master hangs up the slave, discarding unread data, but
+        // the peer keeps it open until the guest disconnects
+        let host = spawn_peer(move || SerialConnection::new(master), echo);
+
+        // Bigger than the pty buffer, to exercise short reads and writes
+        let body = "x".repeat(64 * 1024);
+        check_echo(guest, Request::post("/serial".to_string(), body.into()));
+
+        host.join().unwrap();
+    }
+
+    #[test]
+    fn test_serial_read_timeout() {
+        let (master, slave) = openpty();
+
+        let mut guest = SerialConnection::new(slave);
+        guest.configure(&SerialConfig::default()).unwrap();
+        guest
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+
+        let mut buf = [0u8; 16];
+        assert!(matches!(guest.read(&mut buf), Err(Error::WouldBlock)));
+
+        // Without a timeout, reading nothing is the end of file
+        guest.set_read_timeout(None).unwrap();
+        drop(master);
+        assert_eq!(guest.read(&mut buf).unwrap(), 0);
+    }
+}
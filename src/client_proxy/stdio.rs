@@ -0,0 +1,99 @@
+use std::io::{self, Read as IoRead, Stdin, Stdout, Write as IoWrite};
+
+use super::{Connection, Error, Read, Write};
+
+/// Connection over a pair of `std::io` streams, e.g. the two ends of a pipe
+/// connected to a guest serial port by the VMM.
+pub struct IoConnection<R: IoRead, W: IoWrite> {
+    reader: R,
+    writer: W,
+}
+
+/// Connection over the standard input and output of the process.
+pub type StdioConnection = IoConnection<Stdin, Stdout>;
+
+impl<R: IoRead, W: IoWrite> IoConnection<R, W> {
+    pub fn new(reader: R, writer: W) -> Self {
+        IoConnection { reader, writer }
+    }
+
+    pub fn into_inner(self) -> (R, W) {
+        (self.reader, self.writer)
+    }
+}
+
+impl StdioConnection {
+    /// Creates a connection over stdin/stdout, so the proxy can run as a
+    /// helper process of the VMM. Nothing else must be written to stdout.
+    pub fn stdio() -> Self {
+        IoConnection::new(io::stdin(), io::stdout())
+    }
+}
+
+impl<R: IoRead, W: IoWrite> Write for IoConnection<R, W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        loop {
+            match self.writer.write(buf) {
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                ret => return ret.map_err(|e| Error::WriteError(e.into())),
+            }
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.writer.flush().map_err(|e| Error::FlushError(e.into()))
+    }
+}
+
+impl<R: IoRead, W: IoWrite> Read for IoConnection<R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        loop {
+            match self.reader.read(buf) {
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                ret => return ret.map_err(|e| Error::ReadError(e.into())),
+            }
+        }
+    }
+}
+
+impl<R: IoRead, W: IoWrite> Connection for IoConnection<R, W> {}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Cursor, os::unix::net::UnixStream};
+
+    use super::*;
+    use crate::client_proxy::{
+        tests::{check_echo, echo, spawn_peer},
+        Proxy, Request,
+    };
+
+    #[test]
+    fn test_io_buffers() {
+        let req = Request::get("/io".to_string());
+
+        let mut proxy = Proxy::new(IoConnection::new(io::empty(), Vec::new()));
+        proxy.write_message(&req).unwrap();
+        let (_, written) = proxy.into_inner().into_inner();
+
+        let mut proxy = Proxy::new(IoConnection::new(Cursor::new(written), io::sink()));
+        let req2: Request = proxy.read_message().unwrap();
+        assert_eq!(req, req2);
+        assert!(matches!(proxy.read_json(), Err(Error::Eof)));
+    }
+
+    #[test]
+    fn test_io_pipe() {
+        let (guest, host) = UnixStream::pair().unwrap();
+
+        let host = spawn_peer(
+            move || IoConnection::new(host.try_clone().unwrap(), host),
+            echo,
+        );
+
+        let conn = IoConnection::new(guest.try_clone().unwrap(), guest);
+        check_echo(conn, Request::get("/pipe".to_string()));
+
+        host.join().unwrap();
+    }
+}
@@ -0,0 +1,125 @@
+use std::{
+    io::{self, Read as IoRead, Write as IoWrite},
+    net::{TcpStream, ToSocketAddrs},
+    time::Duration,
+};
+
+use super::{read_error, Connection, Error, Read, Write};
+
+/// Connection over a TCP stream, e.g. a guest serial port exposed by the VMM
+/// as a TCP chardev.
+pub struct TcpConnection(pub TcpStream);
+
+impl TcpConnection {
+    /// Connects to `addr`, trying each resolved address in turn.
+    ///
+    /// If `timeout` is not `None`, each connection attempt fails after that
+    /// time. TCP_NODELAY is enabled, since the proxy protocol exchanges small
+    /// messages and waits for the reply after each of them.
+    pub fn connect<A: ToSocketAddrs>(addr: A, timeout: Option<Duration>) -> io::Result<Self> {
+        let mut last_err = None;
+
+        for addr in addr.to_socket_addrs()? {
+            let stream = match timeout {
+                Some(timeout) => TcpStream::connect_timeout(&addr, timeout),
+                None => TcpStream::connect(addr),
+            };
+
+            match stream {
+                Ok(stream) => {
+                    let conn = TcpConnection(stream);
+                    conn.set_nodelay(true)?;
+                    return Ok(conn);
+                }
+                Err(e) => last_err = Some(e),
+            }
+        }
+
+        Err(last_err.unwrap_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "no addresses to connect to")
+        }))
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.0.set_read_timeout(timeout)
+    }
+
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.0.set_write_timeout(timeout)
+    }
+
+    pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        self.0.set_nodelay(nodelay)
+    }
+}
+
+impl Write for TcpConnection {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        self.0.write(buf).map_err(|e| Error::WriteError(e.into()))
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.0.flush().map_err(|e| Error::FlushError(e.into()))
+    }
+}
+
+impl Read for TcpConnection {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        self.0.read(buf).map_err(read_error)
+    }
+}
+
+impl Connection for TcpConnection {
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> Result<(), Error> {
+        TcpConnection::set_read_timeout(self, timeout).map_err(|e| Error::ReadError(e.into()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use super::*;
+    use crate::client_proxy::{
+        std_clock::StdClock,
+        tests::{check_echo, echo, spawn_peer},
+        Proxy, Request,
+    };
+
+    #[test]
+    fn test_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let host = spawn_peer(move || TcpConnection(listener.accept().unwrap().0), echo);
+
+        let conn = TcpConnection::connect(addr, Some(Duration::from_secs(5))).unwrap();
+        assert!(conn.0.nodelay().unwrap());
+        conn.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        conn.set_write_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+
+        check_echo(conn, Request::get("/tcp".to_string()));
+
+        host.join().unwrap();
+    }
+
+    #[test]
+    fn test_tcp_read_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let conn = TcpConnection::connect(addr, None).unwrap();
+        conn.set_read_timeout(Some(Duration::from_millis(10)))
+            .unwrap();
+        // Keep the peer open, but silent
+        let _peer = listener.accept().unwrap();
+
+        let mut proxy = Proxy::new(conn);
+        assert!(matches!(proxy.read_json(), Err(Error::WouldBlock)));
+
+        proxy.set_clock(StdClock::new());
+        proxy.set_timeout(Some(50));
+        assert!(matches!(proxy.read_json(), Err(Error::Timeout)));
+    }
+}
@@ -0,0 +1,281 @@
+use std::{
+    io, mem,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    time::Duration,
+};
+
+pub use libc::{VMADDR_CID_ANY, VMADDR_CID_HOST, VMADDR_CID_LOCAL, VMADDR_PORT_ANY};
+
+use super::{read_error, Connection, Error, ErrorKind, Read, Write};
+
+/// Address of an AF_VSOCK socket.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct VsockAddr {
+    pub cid: u32,
+    pub port: u32,
+}
+
+impl VsockAddr {
+    pub fn new(cid: u32, port: u32) -> Self {
+        VsockAddr { cid, port }
+    }
+
+    fn to_sockaddr(self) -> libc::sockaddr_vm {
+        // SAFETY: sockaddr_vm is a plain C struct, all zeroes is a valid value
+        let mut addr: libc::sockaddr_vm = unsafe { mem::zeroed() };
+        addr.svm_family = libc::AF_VSOCK as libc::sa_family_t;
+        addr.svm_cid = self.cid;
+        addr.svm_port = self.port;
+        addr
+    }
+
+    fn from_sockaddr(addr: &libc::sockaddr_vm) -> Self {
+        VsockAddr {
+            cid: addr.svm_cid,
+            port: addr.svm_port,
+        }
+    }
+}
+
+fn cvt<T: Default + PartialOrd>(ret: T) -> io::Result<T> {
+    if ret < T::default() {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+fn vsock_socket() -> io::Result<OwnedFd> {
+    // SAFETY: plain syscall, the returned descriptor is owned by OwnedFd
+    let fd =
+        cvt(unsafe { libc::socket(libc::AF_VSOCK, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) })?;
+
+    // SAFETY: `fd` is a valid descriptor just returned by socket()
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+fn sockname(
+    fd: &OwnedFd,
+    f: unsafe extern "C" fn(libc::c_int, *mut libc::sockaddr, *mut libc::socklen_t) -> libc::c_int,
+) -> io::Result<VsockAddr> {
+    // SAFETY: sockaddr_vm is a plain C struct, all zeroes is a valid value
+    let mut addr: libc::sockaddr_vm = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::sockaddr_vm>() as libc::socklen_t;
+
+    // SAFETY: `addr` and `len` describe a valid sockaddr_vm buffer
+    cvt(unsafe {
+        f(
+            fd.as_raw_fd(),
+            &mut addr as *mut _ as *mut libc::sockaddr,
+            &mut len,
+        )
+    })?;
+
+    Ok(VsockAddr::from_sockaddr(&addr))
+}
+
+/// Connection over an AF_VSOCK stream socket, for confidential guests running
+/// Linux userspace.
+pub struct VsockConnection(OwnedFd);
+
+impl VsockConnection {
+    /// Connects to `port` of the VM with context ID `cid` (e.g.
+    /// [`VMADDR_CID_HOST`] to reach the host).
+    pub fn connect(cid: u32, port: u32) -> io::Result<Self> {
+        let fd = vsock_socket()?;
+        let addr = VsockAddr::new(cid, port).to_sockaddr();
+
+        loop {
+            // SAFETY: `addr` is a valid sockaddr_vm for the whole call
+            let ret = unsafe {
+                libc::connect(
+                    fd.as_raw_fd(),
+                    &addr as *const _ as *const libc::sockaddr,
+                    mem::size_of::<libc::sockaddr_vm>() as libc::socklen_t,
+                )
+            };
+            match cvt(ret) {
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+                Ok(_) => return Ok(VsockConnection(fd)),
+            }
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<VsockAddr> {
+        sockname(&self.0, libc::getsockname)
+    }
+
+    pub fn peer_addr(&self) -> io::Result<VsockAddr> {
+        sockname(&self.0, libc::getpeername)
+    }
+}
+
+impl Write for VsockConnection {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        loop {
+            // SAFETY: `buf` is valid for reads of `buf.len()` bytes
+            let ret = unsafe { libc::write(self.0.as_raw_fd(), buf.as_ptr().cast(), buf.len()) };
+            match cvt(ret) {
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(Error::WriteError(e.into())),
+                Ok(n) => return Ok(n as usize),
+            }
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl Read for VsockConnection {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        loop {
+            // SAFETY: `buf` is valid for writes of `buf.len()` bytes
+            let ret = unsafe { libc::read(self.0.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len()) };
+            match cvt(ret) {
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(read_error(e)),
+                Ok(n) => return Ok(n as usize),
+            }
+        }
+    }
+}
+
+impl Connection for VsockConnection {
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> Result<(), Error> {
+        // A zero timeval blocks forever, so `None` maps to it while a zero
+        // Duration is rejected like std does
+        let tv = match timeout {
+            Some(timeout) if timeout.is_zero() => {
+                return Err(Error::ReadError(ErrorKind::InvalidInput.into()));
+            }
+            Some(timeout) => libc::timeval {
+                tv_sec: timeout.as_secs().try_into().unwrap_or(libc::time_t::MAX),
+                tv_usec: timeout.subsec_micros().into(),
+            },
+            None => libc::timeval {
+                tv_sec: 0,
+                tv_usec: 0,
+            },
+        };
+
+        // SAFETY: `tv` is valid for reads of its size for the whole call
+        cvt(unsafe {
+            libc::setsockopt(
+                self.0.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_RCVTIMEO,
+                (&tv as *const libc::timeval).cast(),
+                mem::size_of::<libc::timeval>() as libc::socklen_t,
+            )
+        })
+        .map(|_| ())
+        .map_err(|e| Error::ReadError(e.into()))
+    }
+}
+
+/// AF_VSOCK socket listening for guest connections.
+pub struct VsockListener(OwnedFd);
+
+impl VsockListener {
+    /// Listens on `port` of the local context ID `cid`, or any local CID if
+    /// `cid` is [`VMADDR_CID_ANY`]. If `port` is [`VMADDR_PORT_ANY`], a free
+    /// port is assigned (see [`VsockListener::local_addr`]).
+    pub fn bind(cid: u32, port: u32) -> io::Result<Self> {
+        let fd = vsock_socket()?;
+        let addr = VsockAddr::new(cid, port).to_sockaddr();
+
+        // SAFETY: `addr` is a valid sockaddr_vm for the whole call
+        cvt(unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                &addr as *const _ as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_vm>() as libc::socklen_t,
+            )
+        })?;
+        // SAFETY: plain syscall on a valid descriptor
+        cvt(unsafe { libc::listen(fd.as_raw_fd(), 128) })?;
+
+        Ok(VsockListener(fd))
+    }
+
+    pub fn local_addr(&self) -> io::Result<VsockAddr> {
+        sockname(&self.0, libc::getsockname)
+    }
+
+    /// Waits for a new connection, returning it with the address of the peer.
+    pub fn accept(&self) -> io::Result<(VsockConnection, VsockAddr)> {
+        // SAFETY: sockaddr_vm is a plain C struct, all zeroes is a valid value
+        let mut addr: libc::sockaddr_vm = unsafe { mem::zeroed() };
+        let mut len = mem::size_of::<libc::sockaddr_vm>() as libc::socklen_t;
+
+        loop {
+            // SAFETY: `addr` and `len` describe a valid sockaddr_vm buffer
+            let ret = unsafe {
+                libc::accept4(
+                    self.0.as_raw_fd(),
+                    &mut addr as *mut _ as *mut libc::sockaddr,
+                    &mut len,
+                    libc::SOCK_CLOEXEC,
+                )
+            };
+            match cvt(ret) {
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+                Ok(fd) => {
+                    // SAFETY: `fd` is a valid descriptor just returned by accept4()
+                    let conn = VsockConnection(unsafe { OwnedFd::from_raw_fd(fd) });
+                    return Ok((conn, VsockAddr::from_sockaddr(&addr)));
+                }
+            }
+        }
+    }
+
+    /// Iterator over the incoming connections, see [`VsockListener::accept`].
+    pub fn incoming(&self) -> impl Iterator<Item = io::Result<VsockConnection>> + '_ {
+        core::iter::repeat_with(move || self.accept().map(|(conn, _)| conn))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client_proxy::{
+        tests::{check_echo, echo, spawn_peer},
+        Request,
+    };
+
+    // Requires the vsock loopback transport (vsock_loopback module), skipped
+    // if it is not available.
+    #[test]
+    fn test_vsock_loopback() {
+        let Ok(listener) = VsockListener::bind(VMADDR_CID_ANY, VMADDR_PORT_ANY) else {
+            return;
+        };
+        let port = listener.local_addr().unwrap().port;
+
+        let Ok(conn) = VsockConnection::connect(VMADDR_CID_LOCAL, port) else {
+            return;
+        };
+
+        let host = spawn_peer(
+            move || {
+                let (conn, peer) = listener.accept().unwrap();
+                assert_eq!(peer.cid, VMADDR_CID_LOCAL);
+                conn
+            },
+            echo,
+        );
+
+        assert_eq!(
+            conn.peer_addr().unwrap(),
+            VsockAddr::new(VMADDR_CID_LOCAL, port)
+        );
+
+        check_echo(conn, Request::get("/vsock".to_string()));
+
+        host.join().unwrap();
+    }
+}
@@ -1,9 +1,55 @@
 use serde_json::Value;
 
-use crate::lib::String;
+use crate::{
+    lib::{fmt, Debug},
+    secret::SecretString,
+};
+
+#[derive(Debug)]
+pub enum Error {
+    JsonError(serde_json::Error),
+}
+
+impl Error {
+    /// Stable code of the error, see [`crate::Error::code`].
+    pub fn code(&self) -> u16 {
+        match self {
+            Self::JsonError(_) => 1,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::JsonError(e) => Some(e),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::JsonError(je) => write!(f, "Workload serialization failed - {je}"),
+        }
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Self::JsonError(e)
+    }
+}
+
+impl From<Error> for crate::Error {
+    fn from(e: Error) -> Self {
+        Self::CR(e)
+    }
+}
 
 pub trait TeeRegistration {
-    fn register(&self, measurement: &[u8], secret: String) -> Value;
+    fn register(&self, measurement: &[u8], secret: SecretString) -> Result<Value, Error>;
 }
 
 pub struct ClientRegistration {}
@@ -19,7 +65,11 @@ impl ClientRegistration {
         Self {}
     }
 
-    pub fn register(measurement: &[u8], secret: String, tee: &dyn TeeRegistration) -> Value {
+    pub fn register(
+        measurement: &[u8],
+        secret: SecretString,
+        tee: &dyn TeeRegistration,
+    ) -> Result<Value, Error> {
         tee.register(measurement, secret)
     }
 }
@@ -1,28 +1,124 @@
 use base64ct::{Base64, Encoding};
-pub use kbs_types::{Attestation, Challenge, Request, Tee, TeePubKey};
+#[cfg(feature = "alloc")]
+pub use kbs_types::Tee;
+#[cfg(feature = "alloc")]
 use num_bigint::BigUint;
-use serde_json::{json, Value};
+#[cfg(feature = "alloc")]
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "alloc")]
+use serde_json::Value;
+use zeroize::Zeroize;
 
-use crate::lib::{fmt, Debug, String, ToString, Vec};
+use crate::lib::{fmt, Debug};
+#[cfg(feature = "jws")]
+use crate::{
+    jws::{self, Jws, VerifyingKey},
+    lib::Vec,
+};
+#[cfg(feature = "alloc")]
+use crate::{
+    lib::{vec, String, ToString},
+    secret::SecretBytes,
+};
+
+#[cfg(feature = "heapless")]
+pub mod heapless;
 
 #[derive(Debug)]
 pub enum Error {
+    #[cfg(feature = "alloc")]
     JsonError(serde_json::Error),
     HexError(hex::FromHexError),
+    Base64Error(base64ct::Error),
+    BufferTooSmall(usize, usize),
+    #[cfg(feature = "jws")]
+    JwsError(jws::Error),
+    #[cfg(any(feature = "jws", feature = "heapless"))]
+    Utf8Error(core::str::Utf8Error),
+    #[cfg(feature = "heapless")]
+    JsonSerError(serde_json_core::ser::Error),
+    #[cfg(feature = "heapless")]
+    JsonDeError(serde_json_core::de::Error),
+    UnsupportedVersion,
+    ChallengeExpected,
+}
+
+impl Error {
+    /// Stable code of the error, see [`crate::Error::code`].
+    pub fn code(&self) -> u16 {
+        match self {
+            #[cfg(feature = "alloc")]
+            Self::JsonError(_) => 1,
+            Self::HexError(_) => 2,
+            Self::Base64Error(_) => 3,
+            Self::BufferTooSmall(..) => 4,
+            #[cfg(feature = "jws")]
+            Self::JwsError(_) => 5,
+            #[cfg(any(feature = "jws", feature = "heapless"))]
+            Self::Utf8Error(_) => 6,
+            #[cfg(feature = "heapless")]
+            Self::JsonSerError(_) => 7,
+            #[cfg(feature = "heapless")]
+            Self::JsonDeError(_) => 8,
+            Self::UnsupportedVersion => 9,
+            Self::ChallengeExpected => 10,
+        }
+    }
 }
 
 #[cfg(feature = "std")]
-impl std::error::Error for Error {}
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            #[cfg(feature = "alloc")]
+            Self::JsonError(e) => Some(e),
+            Self::HexError(e) => Some(e),
+            Self::Base64Error(e) => Some(e),
+            Self::BufferTooSmall(..) => None,
+            #[cfg(feature = "jws")]
+            Self::JwsError(e) => Some(e),
+            #[cfg(any(feature = "jws", feature = "heapless"))]
+            Self::Utf8Error(e) => Some(e),
+            #[cfg(feature = "heapless")]
+            Self::JsonSerError(e) => Some(e),
+            #[cfg(feature = "heapless")]
+            Self::JsonDeError(e) => Some(e),
+            Self::UnsupportedVersion | Self::ChallengeExpected => None,
+        }
+    }
+}
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
+            #[cfg(feature = "alloc")]
             Self::JsonError(je) => write!(f, "Malformed JSON - {je}"),
             Self::HexError(he) => write!(f, "Converion to hex failed - {he}"),
+            Self::Base64Error(be) => write!(f, "Malformed base64 secret - {be}"),
+            Self::BufferTooSmall(needed, size) => write!(
+                f,
+                "Data of {needed} bytes does not fit in a buffer of {size} bytes"
+            ),
+            #[cfg(feature = "jws")]
+            Self::JwsError(je) => write!(f, "Untrusted KBS response - {je}"),
+            #[cfg(any(feature = "jws", feature = "heapless"))]
+            Self::Utf8Error(ue) => write!(f, "Malformed UTF-8 payload - {ue}"),
+            #[cfg(feature = "heapless")]
+            Self::JsonSerError(je) => write!(f, "JSON serialization failed - {je}"),
+            #[cfg(feature = "heapless")]
+            Self::JsonDeError(je) => write!(f, "Malformed JSON - {je}"),
+            Self::UnsupportedVersion => write!(
+                f,
+                "None of the protocol versions of the client is supported by the KBS"
+            ),
+            Self::ChallengeExpected => {
+                write!(f, "The attestation needs the nonce of the challenge")
+            }
         }
     }
 }
 
+#[cfg(feature = "alloc")]
 impl From<serde_json::Error> for Error {
     fn from(e: serde_json::Error) -> Self {
         Self::JsonError(e)
@@ -35,45 +131,369 @@ impl From<hex::FromHexError> for Error {
     }
 }
 
+impl From<base64ct::Error> for Error {
+    fn from(e: base64ct::Error) -> Self {
+        Self::Base64Error(e)
+    }
+}
+
+#[cfg(feature = "heapless")]
+impl From<serde_json_core::ser::Error> for Error {
+    fn from(e: serde_json_core::ser::Error) -> Self {
+        Self::JsonSerError(e)
+    }
+}
+
+#[cfg(feature = "heapless")]
+impl From<serde_json_core::de::Error> for Error {
+    fn from(e: serde_json_core::de::Error) -> Self {
+        Self::JsonDeError(e)
+    }
+}
+
+#[cfg(feature = "jws")]
+impl From<jws::Error> for Error {
+    fn from(e: jws::Error) -> Self {
+        Self::JwsError(e)
+    }
+}
+
 impl From<Error> for crate::Error {
     fn from(e: Error) -> Self {
         Self::CS(e)
     }
 }
 
+/// Encoding of the secret returned by [`TeeSession::secret`].
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum SecretEncoding {
+    #[default]
+    Hex,
+    Base64,
+}
+
+impl SecretEncoding {
+    /// Maximum size of the data encoded in `encoded`.
+    pub fn max_decoded_len(&self, encoded: &str) -> usize {
+        match self {
+            Self::Hex => encoded.len() / 2,
+            Self::Base64 => encoded.len().div_ceil(4) * 3,
+        }
+    }
+
+    /// Decodes `encoded` into `out`, returning the decoded length.
+    ///
+    /// On failure, whatever was written in `out` is zeroed.
+    pub fn decode_into(&self, encoded: &str, out: &mut [u8]) -> Result<usize, Error> {
+        let ret = match self {
+            Self::Hex => {
+                let len = self.max_decoded_len(encoded);
+                match out.get_mut(..len) {
+                    Some(out) => hex::decode_to_slice(encoded, out)
+                        .map(|_| len)
+                        .map_err(Error::from),
+                    None => Err(Error::BufferTooSmall(len, out.len())),
+                }
+            }
+            Self::Base64 => Base64::decode(encoded, out)
+                .map(|decoded| decoded.len())
+                .map_err(|e| match e {
+                    base64ct::Error::InvalidLength if out.len() < self.max_decoded_len(encoded) => {
+                        Error::BufferTooSmall(self.max_decoded_len(encoded), out.len())
+                    }
+                    e => Error::from(e),
+                }),
+        };
+
+        if ret.is_err() {
+            out.zeroize();
+        }
+
+        ret
+    }
+}
+
+/// Version of the KBS protocol, which defines the shape of its messages.
+///
+/// The messages of each version are defined in this module rather than taken
+/// from `kbs-types`, whose revisions each implement a single version.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub enum ProtocolVersion {
+    /// Public key and evidence at the top of the attestation, nested JSON
+    /// documents embedded as strings by default.
+    V0_1_0,
+    /// Public key and nonce in the `runtime-data` of the attestation, along
+    /// with the optional `init-data`, nested JSON documents as objects.
+    V0_2_0,
+}
+
+impl ProtocolVersion {
+    /// Versions implemented by the crate, newest first.
+    pub const SUPPORTED: &'static [ProtocolVersion] = &[Self::V0_2_0, Self::V0_1_0];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::V0_1_0 => "0.1.0",
+            Self::V0_2_0 => "0.2.0",
+        }
+    }
+
+    /// How the extra parameters and the evidence are embedded, given the
+    /// `preferred` embedding of the client.
+    pub fn json_embedding(&self, preferred: JsonEmbedding) -> JsonEmbedding {
+        match self {
+            Self::V0_1_0 => preferred,
+            Self::V0_2_0 => JsonEmbedding::Object,
+        }
+    }
+
+    /// Version following `current` in `versions`, after the KBS rejected
+    /// it, or the first one if no version was tried yet.
+    fn fallback(
+        current: Option<ProtocolVersion>,
+        versions: &[ProtocolVersion],
+    ) -> Result<ProtocolVersion, Error> {
+        let next = match current {
+            Some(current) => versions.iter().skip_while(|&&v| v != current).nth(1),
+            None => versions.first(),
+        };
+
+        next.copied().ok_or(Error::UnsupportedVersion)
+    }
+}
+
+impl fmt::Display for ProtocolVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// How the JSON documents nested in the KBS messages, i.e. the extra
+/// parameters and the evidence, are embedded in them.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum JsonEmbedding {
+    /// Serialized in a string, as expected by servers of the `0.1.0`
+    /// protocol.
+    #[default]
+    String,
+    /// As a JSON object, without escaping it.
+    Object,
+}
+
+/// JSON document nested in a KBS message, see [`JsonEmbedding`].
+#[cfg(feature = "alloc")]
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum Embedded {
+    String(String),
+    Object(Value),
+}
+
+#[cfg(feature = "alloc")]
+impl Embedded {
+    pub fn new(value: Value, embedding: JsonEmbedding) -> Self {
+        match embedding {
+            JsonEmbedding::String => Self::String(value.to_string()),
+            JsonEmbedding::Object => Self::Object(value),
+        }
+    }
+}
+
+/// Body of the `auth` request (`kbs_types::Request`).
+#[cfg(feature = "alloc")]
+#[derive(Serialize, Debug)]
+pub struct Request {
+    pub version: String,
+    pub tee: Tee,
+    #[serde(rename = "extra-params")]
+    pub extra_params: Embedded,
+}
+
+/// Body of the `auth` response (`kbs_types::Challenge`), whose extra
+/// parameters are a string or an object depending on the version.
+#[cfg(feature = "alloc")]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Challenge {
+    pub nonce: String,
+    #[serde(rename = "extra-params", default)]
+    pub extra_params: Value,
+}
+
+/// Public key of the TEE, as a JWK (`kbs_types::TeePubKey`).
+#[cfg(feature = "alloc")]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct TeePubKey {
+    pub kty: String,
+    pub alg: String,
+    #[serde(rename = "n")]
+    pub k_mod: String,
+    #[serde(rename = "e")]
+    pub k_exp: String,
+}
+
+/// Initialization data of the TEE (`kbs_types::InitData`), since `0.2.0`.
+#[cfg(feature = "alloc")]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct InitData {
+    pub format: String,
+    pub body: String,
+}
+
+/// Data bound to the evidence (`kbs_types::RuntimeData`), since `0.2.0`.
+#[cfg(feature = "alloc")]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct RuntimeData {
+    pub nonce: String,
+    #[serde(rename = "tee-pubkey")]
+    pub tee_pubkey: TeePubKey,
+}
+
+/// Body of the `attest` request (`kbs_types::Attestation`), for each
+/// [`ProtocolVersion`].
+#[cfg(feature = "alloc")]
+#[derive(Serialize, Debug)]
+#[serde(untagged)]
+pub enum Attestation {
+    V0_1_0 {
+        #[serde(rename = "tee-pubkey")]
+        tee_pubkey: TeePubKey,
+        #[serde(rename = "tee-evidence")]
+        tee_evidence: Embedded,
+    },
+    V0_2_0 {
+        #[serde(rename = "init-data", skip_serializing_if = "Option::is_none")]
+        init_data: Option<InitData>,
+        #[serde(rename = "runtime-data")]
+        runtime_data: RuntimeData,
+        #[serde(rename = "tee-evidence")]
+        tee_evidence: Value,
+    },
+}
+
+#[cfg(feature = "alloc")]
 pub trait TeeSession {
-    fn version(&self) -> String;
     fn tee(&self) -> Tee;
     fn extra_params(&self) -> Value;
     fn evidence(&self) -> Value;
-    fn secret(&self, data: String) -> Result<String, Error>;
+    /// Extracts the encoded secret from the KBS response `data`.
+    fn secret<'d>(&self, data: &'d [u8]) -> Result<&'d str, Error>;
+
+    fn secret_encoding(&self) -> SecretEncoding {
+        SecretEncoding::Hex
+    }
+
+    /// How the server expects the extra parameters and the evidence, when
+    /// the protocol version leaves the choice.
+    fn json_embedding(&self) -> JsonEmbedding {
+        JsonEmbedding::String
+    }
+
+    /// Versions of the protocol supported by the client, newest first.
+    fn versions(&self) -> &[ProtocolVersion] {
+        ProtocolVersion::SUPPORTED
+    }
+
+    fn init_data(&self) -> Option<InitData> {
+        None
+    }
 }
 
-pub struct ClientSession {}
+/// KBS session, which starts with the newest protocol version of the client
+/// and falls back to older ones while the KBS rejects the `auth` request
+/// (see [`ClientSession::fallback`]).
+#[cfg(feature = "alloc")]
+pub struct ClientSession {
+    #[cfg(feature = "jws")]
+    server_key: Option<VerifyingKey>,
+    version: Option<ProtocolVersion>,
+    nonce: Option<String>,
+}
 
+#[cfg(feature = "alloc")]
 impl Default for ClientSession {
     fn default() -> Self {
         Self::new()
     }
 }
 
+#[cfg(feature = "alloc")]
 impl ClientSession {
     pub fn new() -> Self {
-        ClientSession {}
+        ClientSession {
+            #[cfg(feature = "jws")]
+            server_key: None,
+            version: None,
+            nonce: None,
+        }
     }
 
-    pub fn request(&self, tee: &dyn TeeSession) -> Result<Value, Error> {
-        let request = Request {
-            version: tee.version(),
-            tee: tee.tee(),
-            extra_params: json!(tee.extra_params()).to_string(),
-        };
+    /// Creates a session that only accepts the challenge and the secret if
+    /// they are signed by the KBS with `server_key` (see [`crate::jws`]).
+    #[cfg(feature = "jws")]
+    pub fn with_server_key(server_key: VerifyingKey) -> Self {
+        ClientSession {
+            server_key: Some(server_key),
+            ..Self::new()
+        }
+    }
+
+    /// Returns the payload of `data` if a server key is pinned, after
+    /// verifying its signature, otherwise `data` itself.
+    #[cfg(feature = "jws")]
+    fn verified(&self, data: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        match &self.server_key {
+            Some(key) => {
+                let jws: Jws = serde_json::from_slice(data)?;
+                Ok(Some(jws.verify(key)?))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Version of the protocol used by the session, once negotiated.
+    pub fn version(&self) -> Option<ProtocolVersion> {
+        self.version
+    }
+
+    fn current_version(&self, tee: &dyn TeeSession) -> Result<ProtocolVersion, Error> {
+        match self.version {
+            Some(version) => Ok(version),
+            None => ProtocolVersion::fallback(None, tee.versions()),
+        }
+    }
+
+    /// Switches to the version following the current one in the versions of
+    /// `tee`, after the KBS rejected the `auth` request.
+    pub fn fallback(&mut self, tee: &dyn TeeSession) -> Result<ProtocolVersion, Error> {
+        let version = ProtocolVersion::fallback(self.version, tee.versions())?;
+        self.version = Some(version);
+
+        Ok(version)
+    }
+
+    pub fn request(&mut self, tee: &dyn TeeSession) -> Result<Request, Error> {
+        let version = self.current_version(tee)?;
+        self.version = Some(version);
 
-        Ok(json!(request))
+        Ok(Request {
+            version: version.as_str().to_string(),
+            tee: tee.tee(),
+            extra_params: Embedded::new(
+                tee.extra_params(),
+                version.json_embedding(tee.json_embedding()),
+            ),
+        })
     }
 
-    pub fn challenge(&mut self, data: Value) -> Result<String, Error> {
-        let challenge: Challenge = serde_json::from_value(data)?;
+    /// Returns the nonce in the `auth` response `data`.
+    pub fn challenge(&mut self, data: &[u8]) -> Result<String, Error> {
+        #[cfg(feature = "jws")]
+        let payload = self.verified(data)?;
+        #[cfg(feature = "jws")]
+        let data = payload.as_deref().unwrap_or(data);
+
+        let challenge: Challenge = serde_json::from_slice(data)?;
+        self.nonce = Some(challenge.nonce.clone());
 
         Ok(challenge.nonce)
     }
@@ -83,25 +503,73 @@ impl ClientSession {
         k_mod: String,
         k_exp: String,
         tee: &dyn TeeSession,
-    ) -> Result<Value, Error> {
-        let tee_pubkey = TeePubKey {
+    ) -> Result<Attestation, Error> {
+        let tee_pubkey = |alg: &str| TeePubKey {
             kty: "RSA".to_string(),
-            alg: "RSA".to_string(),
+            alg: alg.to_string(),
             k_mod,
             k_exp,
         };
 
-        let attestation = Attestation {
-            tee_pubkey,
-            tee_evidence: tee.evidence().to_string(),
-        };
+        match self.current_version(tee)? {
+            ProtocolVersion::V0_1_0 => Ok(Attestation::V0_1_0 {
+                tee_pubkey: tee_pubkey("RSA"),
+                tee_evidence: Embedded::new(tee.evidence(), tee.json_embedding()),
+            }),
+            ProtocolVersion::V0_2_0 => Ok(Attestation::V0_2_0 {
+                init_data: tee.init_data(),
+                runtime_data: RuntimeData {
+                    nonce: self.nonce.clone().ok_or(Error::ChallengeExpected)?,
+                    tee_pubkey: tee_pubkey("RSA1_5"),
+                },
+                tee_evidence: tee.evidence(),
+            }),
+        }
+    }
+
+    /// Passes the encoded secret in the KBS response `data` to `decode`,
+    /// after verifying its signature if a server key is pinned.
+    fn decode_secret<R>(
+        &self,
+        data: &[u8],
+        tee: &dyn TeeSession,
+        decode: impl FnOnce(&str) -> Result<R, Error>,
+    ) -> Result<R, Error> {
+        #[cfg(feature = "jws")]
+        let payload = self.verified(data)?.map(SecretBytes::new);
+        #[cfg(feature = "jws")]
+        let data = payload.as_ref().map_or(data, |p| p.expose_secret());
 
-        Ok(json!(attestation))
+        decode(tee.secret(data)?)
     }
 
-    pub fn secret(&self, data: String, tee: &dyn TeeSession) -> Result<Vec<u8>, Error> {
-        // TODO: consider using decode_to_slice() to avoid heap allocation
-        Ok(hex::decode(tee.secret(data)?)?)
+    /// Returns the secret in the KBS response `data`.
+    pub fn secret(&self, data: &[u8], tee: &dyn TeeSession) -> Result<SecretBytes, Error> {
+        let encoding = tee.secret_encoding();
+
+        self.decode_secret(data, tee, |encoded| {
+            let mut secret = vec![0u8; encoding.max_decoded_len(encoded)];
+            let len = encoding.decode_into(encoded, &mut secret)?;
+            secret.truncate(len);
+
+            Ok(SecretBytes::new(secret))
+        })
+    }
+
+    /// Decodes the secret straight into `out` (e.g. protected memory), without
+    /// allocating it on the heap.
+    ///
+    /// Returns the length of the secret, see
+    /// [`SecretEncoding::max_decoded_len`] for the size needed.
+    pub fn secret_into(
+        &self,
+        data: &[u8],
+        tee: &dyn TeeSession,
+        out: &mut [u8],
+    ) -> Result<usize, Error> {
+        self.decode_secret(data, tee, |encoded| {
+            tee.secret_encoding().decode_into(encoded, out)
+        })
     }
 
     pub fn encode_key(key: &BigUint) -> Result<String, Error> {
@@ -0,0 +1,262 @@
+//! KBS session without a memory allocator.
+//!
+//! Counterpart of [`super::ClientSession`] where the KBS messages borrow their
+//! strings. The extra parameters and the evidence are typed by the
+//! [`TeeSession`]: embedded as objects, they are serialized along with the
+//! message, while embedded as strings they are first serialized into a buffer
+//! of `N` bytes owned by the [`ClientSession`]. Signed responses (see
+//! `crate::jws`) are not supported in this mode.
+//!
+//! Strings are borrowed from the KBS responses as they are, without
+//! unescaping them, which is fine for nonces and encoded secrets.
+
+use ::heapless::String;
+use serde::{Deserialize, Serialize};
+
+use super::{Error, JsonEmbedding, ProtocolVersion, SecretEncoding};
+
+/// Maximum size of the nonce kept by the [`ClientSession`] for the
+/// `runtime-data` of the `0.2.0` attestation.
+pub const MAX_NONCE_SIZE: usize = 128;
+
+/// JSON document nested in a KBS message, see [`JsonEmbedding`].
+#[derive(Serialize, Debug)]
+#[serde(untagged)]
+pub enum Embedded<'a, T: Serialize> {
+    String(&'a str),
+    Object(T),
+}
+
+/// Body of the `auth` request (`kbs_types::Request`).
+#[derive(Serialize, Debug)]
+pub struct Request<'a, P: Serialize> {
+    pub version: &'a str,
+    pub tee: &'a str,
+    #[serde(rename = "extra-params")]
+    pub extra_params: Embedded<'a, P>,
+}
+
+/// Body of the `auth` response (`kbs_types::Challenge`).
+#[derive(Deserialize, Debug)]
+pub struct Challenge<'a> {
+    pub nonce: &'a str,
+}
+
+/// Public key of the TEE, as a JWK (`kbs_types::TeePubKey`).
+#[derive(Serialize, Debug)]
+pub struct TeePubKey<'a> {
+    pub kty: &'a str,
+    pub alg: &'a str,
+    #[serde(rename = "n")]
+    pub k_mod: &'a str,
+    #[serde(rename = "e")]
+    pub k_exp: &'a str,
+}
+
+/// Initialization data of the TEE (`kbs_types::InitData`), since `0.2.0`.
+#[derive(Serialize, Debug)]
+pub struct InitData<'a> {
+    pub format: &'a str,
+    pub body: &'a str,
+}
+
+/// Data bound to the evidence (`kbs_types::RuntimeData`), since `0.2.0`.
+#[derive(Serialize, Debug)]
+pub struct RuntimeData<'a> {
+    pub nonce: &'a str,
+    #[serde(rename = "tee-pubkey")]
+    pub tee_pubkey: TeePubKey<'a>,
+}
+
+/// Body of the `attest` request (`kbs_types::Attestation`), for each
+/// [`ProtocolVersion`].
+#[derive(Serialize, Debug)]
+#[serde(untagged)]
+pub enum Attestation<'a, E: Serialize> {
+    V0_1_0 {
+        #[serde(rename = "tee-pubkey")]
+        tee_pubkey: TeePubKey<'a>,
+        #[serde(rename = "tee-evidence")]
+        tee_evidence: Embedded<'a, E>,
+    },
+    V0_2_0 {
+        #[serde(rename = "init-data", skip_serializing_if = "Option::is_none")]
+        init_data: Option<InitData<'a>>,
+        #[serde(rename = "runtime-data")]
+        runtime_data: RuntimeData<'a>,
+        #[serde(rename = "tee-evidence")]
+        tee_evidence: E,
+    },
+}
+
+/// Allocation-free counterpart of [`super::TeeSession`].
+pub trait TeeSession {
+    type ExtraParams<'a>: Serialize
+    where
+        Self: 'a;
+    type Evidence<'a>: Serialize
+    where
+        Self: 'a;
+
+    /// Name of the TEE, e.g. `snp`.
+    fn tee(&self) -> &str;
+    fn extra_params(&self) -> Self::ExtraParams<'_>;
+    fn evidence(&self) -> Self::Evidence<'_>;
+    /// Extracts the encoded secret from the KBS response `data`.
+    fn secret<'d>(&self, data: &'d [u8]) -> Result<&'d str, Error>;
+
+    fn secret_encoding(&self) -> SecretEncoding {
+        SecretEncoding::Hex
+    }
+
+    /// How the server expects the extra parameters and the evidence, when
+    /// the protocol version leaves the choice.
+    fn json_embedding(&self) -> JsonEmbedding {
+        JsonEmbedding::String
+    }
+
+    /// Versions of the protocol supported by the client, newest first.
+    fn versions(&self) -> &[ProtocolVersion] {
+        ProtocolVersion::SUPPORTED
+    }
+
+    fn init_data(&self) -> Option<InitData<'_>> {
+        None
+    }
+}
+
+/// KBS session serializing the documents embedded as strings in a buffer of
+/// `N` bytes, which must then fit the evidence.
+///
+/// The protocol version is negotiated as with [`super::ClientSession`].
+pub struct ClientSession<const N: usize> {
+    buf: [u8; N],
+    version: Option<ProtocolVersion>,
+    nonce: Option<String<MAX_NONCE_SIZE>>,
+}
+
+impl<const N: usize> Default for ClientSession<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> ClientSession<N> {
+    pub fn new() -> Self {
+        ClientSession {
+            buf: [0; N],
+            version: None,
+            nonce: None,
+        }
+    }
+
+    fn embed<T: Serialize>(
+        buf: &mut [u8],
+        value: T,
+        embedding: JsonEmbedding,
+    ) -> Result<Embedded<'_, T>, Error> {
+        match embedding {
+            JsonEmbedding::String => {
+                let len = serde_json_core::to_slice(&value, buf)?;
+                let json = core::str::from_utf8(&buf[..len]).map_err(Error::Utf8Error)?;
+
+                Ok(Embedded::String(json))
+            }
+            JsonEmbedding::Object => Ok(Embedded::Object(value)),
+        }
+    }
+
+    /// Version of the protocol used by the session, once negotiated.
+    pub fn version(&self) -> Option<ProtocolVersion> {
+        self.version
+    }
+
+    fn current_version<T: TeeSession>(&self, tee: &T) -> Result<ProtocolVersion, Error> {
+        match self.version {
+            Some(version) => Ok(version),
+            None => ProtocolVersion::fallback(None, tee.versions()),
+        }
+    }
+
+    /// Switches to the version following the current one in the versions of
+    /// `tee`, after the KBS rejected the `auth` request.
+    pub fn fallback<T: TeeSession>(&mut self, tee: &T) -> Result<ProtocolVersion, Error> {
+        let version = ProtocolVersion::fallback(self.version, tee.versions())?;
+        self.version = Some(version);
+
+        Ok(version)
+    }
+
+    pub fn request<'a, T: TeeSession>(
+        &'a mut self,
+        tee: &'a T,
+    ) -> Result<Request<'a, T::ExtraParams<'a>>, Error> {
+        let version = self.current_version(tee)?;
+        self.version = Some(version);
+
+        Ok(Request {
+            version: version.as_str(),
+            tee: tee.tee(),
+            extra_params: Self::embed(
+                &mut self.buf,
+                tee.extra_params(),
+                version.json_embedding(tee.json_embedding()),
+            )?,
+        })
+    }
+
+    /// Returns the nonce in the `auth` response `data`.
+    pub fn challenge<'d>(&mut self, data: &'d [u8]) -> Result<&'d str, Error> {
+        let (challenge, _) = serde_json_core::from_slice::<Challenge>(data)?;
+
+        let mut nonce = String::new();
+        nonce
+            .push_str(challenge.nonce)
+            .map_err(|_| Error::BufferTooSmall(challenge.nonce.len(), MAX_NONCE_SIZE))?;
+        self.nonce = Some(nonce);
+
+        Ok(challenge.nonce)
+    }
+
+    pub fn attestation<'a, T: TeeSession>(
+        &'a mut self,
+        k_mod: &'a str,
+        k_exp: &'a str,
+        tee: &'a T,
+    ) -> Result<Attestation<'a, T::Evidence<'a>>, Error> {
+        let tee_pubkey = |alg| TeePubKey {
+            kty: "RSA",
+            alg,
+            k_mod,
+            k_exp,
+        };
+
+        match self.current_version(tee)? {
+            ProtocolVersion::V0_1_0 => Ok(Attestation::V0_1_0 {
+                tee_pubkey: tee_pubkey("RSA"),
+                tee_evidence: Self::embed(&mut self.buf, tee.evidence(), tee.json_embedding())?,
+            }),
+            ProtocolVersion::V0_2_0 => Ok(Attestation::V0_2_0 {
+                init_data: tee.init_data(),
+                runtime_data: RuntimeData {
+                    nonce: self.nonce.as_deref().ok_or(Error::ChallengeExpected)?,
+                    tee_pubkey: tee_pubkey("RSA1_5"),
+                },
+                tee_evidence: tee.evidence(),
+            }),
+        }
+    }
+
+    /// Decodes the secret in the KBS response `data` into `out`, returning
+    /// its length.
+    pub fn secret_into<T: TeeSession>(
+        &self,
+        data: &[u8],
+        tee: &T,
+        out: &mut [u8],
+    ) -> Result<usize, Error> {
+        let encoded = tee.secret(data)?;
+
+        tee.secret_encoding().decode_into(encoded, out)
+    }
+}
@@ -1,4 +1,9 @@
 use crate::lib::{fmt, Display};
+#[cfg(any(feature = "keybroker", feature = "reference_kbs"))]
+use crate::{
+    client_proxy::{Connection, Error as CPError, Proxy, Request, Response},
+    lib::{String, ToString},
+};
 
 #[cfg(feature = "keybroker")]
 pub mod keybroker;
@@ -6,16 +11,133 @@ pub mod keybroker;
 #[cfg(feature = "reference_kbs")]
 pub mod reference_kbs;
 
+#[cfg(feature = "heapless")]
+pub mod heapless;
+
+/// Default base path of the KBS API.
+pub const DEFAULT_BASE_PATH: &str = "/kbs/v0";
+/// Placeholder replaced by the workload ID of the client in the endpoint
+/// templates.
+///
+/// Clients without a workload ID (e.g. keybroker) fail with
+/// [`CPError::MissingWorkloadId`](crate::client_proxy::Error::MissingWorkloadId)
+/// on templates containing it.
+pub const WORKLOAD_ID: &str = "{workload_id}";
+
+/// Workload ID replacing [`WORKLOAD_ID`] in `template`, for a client with
+/// `workload_id`.
+#[cfg(any(feature = "keybroker", feature = "reference_kbs", feature = "heapless"))]
+fn template_workload_id<'a>(
+    template: &str,
+    workload_id: Option<&'a str>,
+) -> Result<&'a str, crate::client_proxy::Error> {
+    match workload_id {
+        Some(workload_id) => Ok(workload_id),
+        None if template.contains(WORKLOAD_ID) => {
+            Err(crate::client_proxy::Error::MissingWorkloadId)
+        }
+        None => Ok(""),
+    }
+}
+
+/// Passes the pieces of the path `base` + `template` to `push`, replacing
+/// [`WORKLOAD_ID`] with `workload_id`.
+#[cfg(any(feature = "keybroker", feature = "reference_kbs", feature = "heapless"))]
+fn expand_path<E>(
+    base: &str,
+    template: &str,
+    workload_id: &str,
+    mut push: impl FnMut(&str) -> Result<(), E>,
+) -> Result<(), E> {
+    push(base)?;
+
+    let mut parts = template.split(WORKLOAD_ID);
+    if let Some(part) = parts.next() {
+        push(part)?;
+    }
+    for part in parts {
+        push(workload_id)?;
+        push(part)?;
+    }
+
+    Ok(())
+}
+
+/// Paths of the KBS endpoints of a client, for deployments rewriting them
+/// (e.g. behind an ingress).
+///
+/// The endpoints are templates appended to `base`, see [`WORKLOAD_ID`].
+#[cfg(any(feature = "keybroker", feature = "reference_kbs"))]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Endpoints {
+    pub base: String,
+    pub auth: String,
+    pub attest: String,
+    /// Endpoint of the secret.
+    pub key: String,
+}
+
+#[cfg(any(feature = "keybroker", feature = "reference_kbs"))]
+impl Endpoints {
+    /// Endpoints under [`DEFAULT_BASE_PATH`], with `key` as endpoint of the
+    /// secret.
+    pub fn new(key: &str) -> Self {
+        Endpoints {
+            base: DEFAULT_BASE_PATH.to_string(),
+            auth: "/auth".to_string(),
+            attest: "/attest".to_string(),
+            key: key.to_string(),
+        }
+    }
+
+    pub fn base(mut self, base: &str) -> Self {
+        self.base = base.to_string();
+        self
+    }
+
+    /// Path of the endpoint `template` for a client with `workload_id`.
+    pub fn path(&self, template: &str, workload_id: Option<&str>) -> Result<String, CPError> {
+        let workload_id = template_workload_id(template, workload_id)?;
+
+        let mut path = String::new();
+        let Ok(()) = expand_path(&self.base, template, workload_id, |part| {
+            path.push_str(part);
+            Ok::<_, core::convert::Infallible>(())
+        });
+
+        Ok(path)
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum SnpGeneration {
     Milan,
     Genoa,
 }
 
-impl Display for SnpGeneration {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+impl SnpGeneration {
+    pub fn as_str(&self) -> &'static str {
         match self {
-            SnpGeneration::Milan => write!(f, "milan"),
-            SnpGeneration::Genoa => write!(f, "genoa"),
+            SnpGeneration::Milan => "milan",
+            SnpGeneration::Genoa => "genoa",
         }
     }
 }
+
+impl Display for SnpGeneration {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// Sends `req` through `proxy`, failing on an HTTP error status.
+#[cfg(any(feature = "keybroker", feature = "reference_kbs"))]
+fn send<C: Connection>(proxy: &mut Proxy<C>, req: &Request) -> Result<Response, CPError> {
+    let resp = proxy.request(req)?;
+
+    if !resp.is_success() {
+        return Err(CPError::HttpError(resp.status, resp.body));
+    }
+
+    Ok(resp)
+}
@@ -0,0 +1,640 @@
+//! SNP clients without a memory allocator, to be used with the heapless
+//! [`ClientSession`](crate::client_session::heapless::ClientSession) and
+//! [`Proxy`].
+
+use core::convert::Infallible;
+
+use ::heapless::String;
+use serde::{Deserialize, Serialize, Serializer};
+
+use crate::{
+    client_proxy::{
+        heapless::{Proxy, ProxyRequest, Request, Response},
+        Clock, Connection, Error as CPError,
+    },
+    client_session::{
+        heapless::{Attestation, Request as KbsRequest, TeeSession},
+        Error as CSError, ProtocolVersion,
+    },
+    clients::{expand_path, template_workload_id, SnpGeneration, DEFAULT_BASE_PATH},
+    lib::fmt,
+};
+
+/// Size of an SNP attestation report.
+pub const SNP_REPORT_SIZE: usize = 1184;
+
+/// Maximum size of the endpoints built by the clients.
+pub const MAX_ENDPOINT_SIZE: usize = 256;
+
+/// Allocation-free counterpart of [`super::Endpoints`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Endpoints<'a> {
+    pub base: &'a str,
+    pub auth: &'a str,
+    pub attest: &'a str,
+    /// Endpoint of the secret.
+    pub key: &'a str,
+}
+
+impl<'a> Endpoints<'a> {
+    /// Endpoints under [`DEFAULT_BASE_PATH`], with `key` as endpoint of the
+    /// secret.
+    pub const fn new(key: &'a str) -> Self {
+        Endpoints {
+            base: DEFAULT_BASE_PATH,
+            auth: "/auth",
+            attest: "/attest",
+            key,
+        }
+    }
+
+    pub fn base(mut self, base: &'a str) -> Self {
+        self.base = base;
+        self
+    }
+
+    /// Path of the endpoint `template` for a client with `workload_id`.
+    pub fn path(
+        &self,
+        template: &str,
+        workload_id: Option<&str>,
+    ) -> Result<String<MAX_ENDPOINT_SIZE>, CPError> {
+        let workload_id = template_workload_id(template, workload_id)?;
+
+        let mut needed = 0;
+        let Ok(()) = expand_path(self.base, template, workload_id, |part| {
+            needed += part.len();
+            Ok::<_, Infallible>(())
+        });
+
+        let mut path = String::new();
+        expand_path(self.base, template, workload_id, |part| path.push_str(part))
+            .map_err(|_| CPError::BufferTooSmall(needed, MAX_ENDPOINT_SIZE))?;
+
+        Ok(path)
+    }
+}
+
+/// Report hex-encoded on the fly while serializing it.
+pub struct HexReport<'a>(Option<&'a [u8; SNP_REPORT_SIZE]>);
+
+impl fmt::Display for HexReport<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in self.0.into_iter().flatten() {
+            write!(f, "{byte:02x}")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Serialize for HexReport<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Evidence of the SNP clients (`kbs_types::SnpAttestation`).
+#[derive(Serialize)]
+pub struct SnpEvidence<'a> {
+    report: HexReport<'a>,
+    cert_chain: &'a str,
+    gen: &'a str,
+}
+
+impl<'a> SnpEvidence<'a> {
+    fn new(gen: SnpGeneration, report: Option<&'a [u8; SNP_REPORT_SIZE]>) -> Self {
+        SnpEvidence {
+            report: HexReport(report),
+            cert_chain: "",
+            gen: gen.as_str(),
+        }
+    }
+}
+
+/// Extra parameters of the reference KBS client (`kbs_types::SnpRequest`).
+#[derive(Serialize)]
+pub struct SnpRequest<'a> {
+    workload_id: &'a str,
+}
+
+/// Response of keybroker (`kbs_types::Response`), of which only the
+/// ciphertext is needed.
+#[derive(Deserialize)]
+struct KbsResponse<'a> {
+    ciphertext: &'a str,
+}
+
+fn send<C: Connection, const N: usize, K: Clock, B: Serialize>(
+    proxy: &mut Proxy<C, N, K>,
+    req: &Request<B>,
+) -> Result<Response<N>, CPError> {
+    let resp = proxy.request(req)?;
+
+    if !resp.is_success() {
+        return Err(CPError::HttpStatus(resp.status));
+    }
+
+    Ok(resp)
+}
+
+fn auth<C: Connection, const N: usize, K: Clock, P: Serialize>(
+    proxy: &mut Proxy<C, N, K>,
+    path: &str,
+    request: &KbsRequest<P>,
+    out: &mut [u8],
+) -> Result<usize, CPError> {
+    send(proxy, &Request::post(path, request))?.body_into(out)
+}
+
+fn attest<C: Connection, const N: usize, K: Clock, E: Serialize>(
+    proxy: &mut Proxy<C, N, K>,
+    path: &str,
+    attestation: &Attestation<E>,
+) -> Result<(), CPError> {
+    send(proxy, &Request::post(path, attestation)).map(|_| ())
+}
+
+pub struct KeybrokerClientSnp<'a> {
+    gen: SnpGeneration,
+    report: Option<[u8; SNP_REPORT_SIZE]>,
+    versions: &'static [ProtocolVersion],
+    endpoints: Endpoints<'a>,
+}
+
+impl<'a> KeybrokerClientSnp<'a> {
+    pub fn new(gen: SnpGeneration) -> Self {
+        KeybrokerClientSnp {
+            gen,
+            report: None,
+            versions: &[ProtocolVersion::V0_1_0],
+            endpoints: Endpoints::new("/resource"),
+        }
+    }
+
+    /// Sends the requests to `endpoints` instead of the default ones.
+    pub fn with_endpoints(mut self, endpoints: Endpoints<'a>) -> Self {
+        self.endpoints = endpoints;
+        self
+    }
+
+    fn path(&self, template: &str) -> Result<String<MAX_ENDPOINT_SIZE>, CPError> {
+        self.endpoints.path(template, None)
+    }
+
+    /// Advertises `versions` of the protocol, newest first, instead of only
+    /// the `0.1.0` version of the server.
+    pub fn with_versions(mut self, versions: &'static [ProtocolVersion]) -> Self {
+        self.versions = versions;
+        self
+    }
+
+    pub fn update_report(&mut self, report: &[u8; SNP_REPORT_SIZE]) {
+        self.report = Some(*report);
+    }
+}
+
+impl<'e> TeeSession for KeybrokerClientSnp<'e> {
+    type ExtraParams<'a>
+        = ()
+    where
+        'e: 'a;
+    type Evidence<'a>
+        = SnpEvidence<'a>
+    where
+        'e: 'a;
+
+    fn tee(&self) -> &str {
+        "snp"
+    }
+
+    fn extra_params(&self) {}
+
+    fn evidence(&self) -> SnpEvidence<'_> {
+        SnpEvidence::new(self.gen, self.report.as_ref())
+    }
+
+    fn versions(&self) -> &[ProtocolVersion] {
+        self.versions
+    }
+
+    fn secret<'d>(&self, data: &'d [u8]) -> Result<&'d str, CSError> {
+        let (resp, _) = serde_json_core::from_slice::<KbsResponse>(data)?;
+
+        Ok(resp.ciphertext)
+    }
+}
+
+impl ProxyRequest for KeybrokerClientSnp<'_> {
+    fn auth<C: Connection, const N: usize, K: Clock, P: Serialize>(
+        &self,
+        proxy: &mut Proxy<C, N, K>,
+        request: &KbsRequest<P>,
+        out: &mut [u8],
+    ) -> Result<usize, CPError> {
+        auth(proxy, &self.path(self.endpoints.auth)?, request, out)
+    }
+
+    fn attest<C: Connection, const N: usize, K: Clock, E: Serialize>(
+        &self,
+        proxy: &mut Proxy<C, N, K>,
+        attestation: &Attestation<E>,
+    ) -> Result<(), CPError> {
+        attest(proxy, &self.path(self.endpoints.attest)?, attestation)
+    }
+
+    fn key<C: Connection, const N: usize, K: Clock>(
+        &self,
+        proxy: &mut Proxy<C, N, K>,
+        out: &mut [u8],
+    ) -> Result<usize, CPError> {
+        send(proxy, &Request::get(&self.path(self.endpoints.key)?))?.secret_body_into(out)
+    }
+}
+
+pub struct ReferenceKBSClientSnp<'a> {
+    workload_id: &'a str,
+    gen: SnpGeneration,
+    report: Option<[u8; SNP_REPORT_SIZE]>,
+    versions: &'static [ProtocolVersion],
+    endpoints: Endpoints<'a>,
+}
+
+impl<'a> ReferenceKBSClientSnp<'a> {
+    pub fn new(gen: SnpGeneration, workload_id: &'a str) -> Self {
+        ReferenceKBSClientSnp {
+            workload_id,
+            gen,
+            report: None,
+            versions: &[ProtocolVersion::V0_1_0],
+            endpoints: Endpoints::new("/key/{workload_id}"),
+        }
+    }
+
+    /// Sends the requests to `endpoints` instead of the default ones.
+    pub fn with_endpoints(mut self, endpoints: Endpoints<'a>) -> Self {
+        self.endpoints = endpoints;
+        self
+    }
+
+    fn path(&self, template: &str) -> Result<String<MAX_ENDPOINT_SIZE>, CPError> {
+        self.endpoints.path(template, Some(self.workload_id))
+    }
+
+    /// Advertises `versions` of the protocol, newest first, instead of only
+    /// the `0.1.0` version of the server.
+    pub fn with_versions(mut self, versions: &'static [ProtocolVersion]) -> Self {
+        self.versions = versions;
+        self
+    }
+
+    pub fn update_report(&mut self, report: &[u8; SNP_REPORT_SIZE]) {
+        self.report = Some(*report);
+    }
+}
+
+impl<'w> TeeSession for ReferenceKBSClientSnp<'w> {
+    type ExtraParams<'a>
+        = SnpRequest<'a>
+    where
+        'w: 'a;
+    type Evidence<'a>
+        = SnpEvidence<'a>
+    where
+        'w: 'a;
+
+    fn tee(&self) -> &str {
+        "snp"
+    }
+
+    fn extra_params(&self) -> SnpRequest<'_> {
+        SnpRequest {
+            workload_id: self.workload_id,
+        }
+    }
+
+    fn evidence(&self) -> SnpEvidence<'_> {
+        SnpEvidence::new(self.gen, self.report.as_ref())
+    }
+
+    fn versions(&self) -> &[ProtocolVersion] {
+        self.versions
+    }
+
+    fn secret<'d>(&self, data: &'d [u8]) -> Result<&'d str, CSError> {
+        let (secret, _) = serde_json_core::from_slice::<&str>(data)?;
+
+        Ok(secret)
+    }
+}
+
+impl ProxyRequest for ReferenceKBSClientSnp<'_> {
+    fn auth<C: Connection, const N: usize, K: Clock, P: Serialize>(
+        &self,
+        proxy: &mut Proxy<C, N, K>,
+        request: &KbsRequest<P>,
+        out: &mut [u8],
+    ) -> Result<usize, CPError> {
+        auth(proxy, &self.path(self.endpoints.auth)?, request, out)
+    }
+
+    fn attest<C: Connection, const N: usize, K: Clock, E: Serialize>(
+        &self,
+        proxy: &mut Proxy<C, N, K>,
+        attestation: &Attestation<E>,
+    ) -> Result<(), CPError> {
+        attest(proxy, &self.path(self.endpoints.attest)?, attestation)
+    }
+
+    fn key<C: Connection, const N: usize, K: Clock>(
+        &self,
+        proxy: &mut Proxy<C, N, K>,
+        out: &mut [u8],
+    ) -> Result<usize, CPError> {
+        send(proxy, &Request::get(&self.path(self.endpoints.key)?))?.secret_body_into(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::net::UnixStream;
+
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::{
+        client_proxy::{
+            tests::spawn_peer, unix::UnixConnection, Request as AllocRequest,
+            Response as AllocResponse, SUPPORTED_CAPABILITIES,
+        },
+        client_session::{
+            heapless::{ClientSession, Embedded},
+            JsonEmbedding,
+        },
+    };
+
+    #[test]
+    fn test_heapless_session() {
+        let (guest, host) = UnixStream::pair().unwrap();
+        let mut report = [0u8; SNP_REPORT_SIZE];
+        report[0] = 42;
+        report[SNP_REPORT_SIZE - 1] = 24;
+        let remote_secret = [0x5au8, 0xa5, 0x00, 0xff];
+
+        let kbs = spawn_peer(
+            move || UnixConnection(host),
+            move |proxy| {
+                let req: AllocRequest = proxy.read_message().unwrap();
+                assert_eq!(req.endpoint, "/kbs/v0/auth");
+                assert_eq!(
+                    req.body,
+                    json!({
+                        "version": "0.1.0",
+                        "tee": "snp",
+                        "extra-params": json!({"workload_id": "snp-workload"}).to_string(),
+                    })
+                );
+                let mut resp =
+                    AllocResponse::new(200, json!({"nonce": "42", "extra-params": ""}).to_string());
+                resp.id = req.id;
+                proxy.write_message(&resp).unwrap();
+
+                let req: AllocRequest = proxy.read_message().unwrap();
+                assert_eq!(req.endpoint, "/kbs/v0/attest");
+                assert_eq!(req.body["tee-pubkey"]["n"], "mod");
+                assert_eq!(req.body["tee-pubkey"]["e"], "exp");
+                let evidence: Value =
+                    serde_json::from_str(req.body["tee-evidence"].as_str().unwrap()).unwrap();
+                assert_eq!(evidence["report"], hex::encode(report));
+                assert_eq!(evidence["gen"], "milan");
+                let mut resp = AllocResponse::new(200, "".to_string());
+                resp.id = req.id;
+                proxy.write_message(&resp).unwrap();
+
+                let req: AllocRequest = proxy.read_message().unwrap();
+                assert_eq!(req.endpoint, "/kbs/v0/key/snp-workload");
+                let mut resp =
+                    AllocResponse::new(200, json!(hex::encode(remote_secret)).to_string());
+                resp.id = req.id;
+                proxy.write_message(&resp).unwrap();
+            },
+        );
+
+        let mut proxy = Proxy::<_, 4096>::new(UnixConnection(guest));
+        proxy.handshake(SUPPORTED_CAPABILITIES).unwrap();
+
+        let mut snp = ReferenceKBSClientSnp::new(SnpGeneration::Milan, "snp-workload");
+        let mut cs = ClientSession::<4096>::new();
+        let mut data = [0u8; 256];
+
+        let request = cs.request(&snp).unwrap();
+        let len = snp.auth(&mut proxy, &request, &mut data).unwrap();
+        assert_eq!(cs.challenge(&data[..len]).unwrap(), "42");
+
+        snp.update_report(&report);
+        let attestation = cs.attestation("mod", "exp", &snp).unwrap();
+        snp.attest(&mut proxy, &attestation).unwrap();
+
+        let len = snp.key(&mut proxy, &mut data).unwrap();
+        let mut secret = [0u8; 16];
+        let secret_len = cs.secret_into(&data[..len], &snp, &mut secret).unwrap();
+        assert_eq!(&secret[..secret_len], &remote_secret);
+
+        kbs.join().unwrap();
+
+        let kb = KeybrokerClientSnp::new(SnpGeneration::Genoa);
+        let request = cs.request(&kb).unwrap();
+        assert!(matches!(request.extra_params, Embedded::String("null")));
+        let resp = json!({
+            "protected": "",
+            "encrypted_key": "",
+            "iv": "",
+            "ciphertext": "5aa5",
+            "tag": "",
+        })
+        .to_string();
+        assert_eq!(kb.secret(resp.as_bytes()).unwrap(), "5aa5");
+    }
+
+    #[test]
+    fn test_object_embedding() {
+        struct ObjectClient(KeybrokerClientSnp<'static>);
+
+        impl TeeSession for ObjectClient {
+            type ExtraParams<'a> = ();
+            type Evidence<'a> = SnpEvidence<'a>;
+
+            fn tee(&self) -> &str {
+                self.0.tee()
+            }
+
+            fn extra_params(&self) {}
+
+            fn evidence(&self) -> SnpEvidence<'_> {
+                self.0.evidence()
+            }
+
+            fn secret<'d>(&self, data: &'d [u8]) -> Result<&'d str, CSError> {
+                self.0.secret(data)
+            }
+
+            fn json_embedding(&self) -> JsonEmbedding {
+                JsonEmbedding::Object
+            }
+
+            fn versions(&self) -> &[ProtocolVersion] {
+                self.0.versions()
+            }
+        }
+
+        let mut report = [0u8; SNP_REPORT_SIZE];
+        report[0] = 0xab;
+        let mut client = ObjectClient(KeybrokerClientSnp::new(SnpGeneration::Milan));
+        client.0.update_report(&report);
+
+        // Nothing is serialized in the session buffer
+        let mut cs = ClientSession::<0>::new();
+        let mut buf = [0u8; 4096];
+
+        let request = cs.request(&client).unwrap();
+        let len = serde_json_core::to_slice(&request, &mut buf).unwrap();
+        let request: Value = serde_json::from_slice(&buf[..len]).unwrap();
+        assert_eq!(request["extra-params"], Value::Null);
+
+        let attestation = cs.attestation("mod", "exp", &client).unwrap();
+        let len = serde_json_core::to_slice(&attestation, &mut buf).unwrap();
+        let attestation: Value = serde_json::from_slice(&buf[..len]).unwrap();
+        assert_eq!(
+            attestation["tee-evidence"],
+            json!({
+                "report": hex::encode(report),
+                "cert_chain": "",
+                "gen": "milan",
+            })
+        );
+    }
+
+    #[test]
+    fn test_heapless_v0_2_0() {
+        let (guest, host) = UnixStream::pair().unwrap();
+        let mut report = [0u8; SNP_REPORT_SIZE];
+        report[1] = 0xcd;
+
+        let kbs = spawn_peer(
+            move || UnixConnection(host),
+            move |proxy| {
+                let req: AllocRequest = proxy.read_message().unwrap();
+                assert_eq!(
+                    req.body,
+                    json!({
+                        "version": "0.2.0",
+                        "tee": "snp",
+                        "extra-params": {"workload_id": "snp-workload"},
+                    })
+                );
+                let challenge = json!({"nonce": "42", "extra-params": {}}).to_string();
+                let mut resp = AllocResponse::new(200, challenge);
+                resp.id = req.id;
+                proxy.write_message(&resp).unwrap();
+
+                let req: AllocRequest = proxy.read_message().unwrap();
+                assert_eq!(
+                    req.body,
+                    json!({
+                        "runtime-data": {
+                            "nonce": "42",
+                            "tee-pubkey": {"kty": "RSA", "alg": "RSA1_5", "n": "mod", "e": "exp"},
+                        },
+                        "tee-evidence": {
+                            "report": hex::encode(report),
+                            "cert_chain": "",
+                            "gen": "milan",
+                        },
+                    })
+                );
+                let mut resp = AllocResponse::new(200, "".to_string());
+                resp.id = req.id;
+                proxy.write_message(&resp).unwrap();
+            },
+        );
+
+        let mut proxy = Proxy::<_, 4096>::new(UnixConnection(guest));
+        proxy.handshake(SUPPORTED_CAPABILITIES).unwrap();
+
+        let mut snp = ReferenceKBSClientSnp::new(SnpGeneration::Milan, "snp-workload")
+            .with_versions(ProtocolVersion::SUPPORTED);
+        let mut cs = ClientSession::<0>::new();
+        let mut data = [0u8; 256];
+
+        let len = snp.negotiate(&mut proxy, &mut cs, &mut data).unwrap();
+        assert_eq!(cs.version(), Some(ProtocolVersion::V0_2_0));
+        assert_eq!(cs.challenge(&data[..len]).unwrap(), "42");
+
+        snp.update_report(&report);
+        let attestation = cs.attestation("mod", "exp", &snp).unwrap();
+        snp.attest(&mut proxy, &attestation).unwrap();
+
+        kbs.join().unwrap();
+    }
+
+    #[test]
+    fn test_heapless_negotiate_rejected() {
+        let (guest, host) = UnixStream::pair().unwrap();
+
+        let kbs = spawn_peer(
+            move || UnixConnection(host),
+            move |proxy| {
+                for version in ["0.2.0", "0.1.0"] {
+                    let req: AllocRequest = proxy.read_message().unwrap();
+                    assert_eq!(req.body["version"], version);
+                    let mut resp = AllocResponse::new(401, "".to_string());
+                    resp.id = req.id;
+                    proxy.write_message(&resp).unwrap();
+                }
+            },
+        );
+
+        let mut proxy = Proxy::<_, 4096>::new(UnixConnection(guest));
+        proxy.handshake(SUPPORTED_CAPABILITIES).unwrap();
+
+        let snp = ReferenceKBSClientSnp::new(SnpGeneration::Milan, "snp-workload")
+            .with_versions(ProtocolVersion::SUPPORTED);
+        let mut cs = ClientSession::<4096>::new();
+        let mut data = [0u8; 256];
+
+        assert!(matches!(
+            snp.negotiate(&mut proxy, &mut cs, &mut data),
+            Err(crate::Error::CP(CPError::HttpStatus(401)))
+        ));
+        assert_eq!(cs.version(), Some(ProtocolVersion::V0_1_0));
+
+        kbs.join().unwrap();
+    }
+
+    #[test]
+    fn test_endpoints() {
+        let endpoints = Endpoints {
+            key: "/workloads/{workload_id}/key/{workload_id}",
+            ..Endpoints::new("").base("/ingress")
+        };
+        assert_eq!(
+            endpoints.path(endpoints.key, Some("snp")).unwrap(),
+            "/ingress/workloads/snp/key/snp"
+        );
+        assert_eq!(
+            endpoints.path(endpoints.auth, None).unwrap(),
+            "/ingress/auth"
+        );
+        assert!(matches!(
+            endpoints.path(endpoints.key, None),
+            Err(CPError::MissingWorkloadId)
+        ));
+
+        let workload_id = "x".repeat(MAX_ENDPOINT_SIZE);
+        assert!(matches!(
+            endpoints.path(endpoints.key, Some(&workload_id)),
+            Err(CPError::BufferTooSmall(needed, MAX_ENDPOINT_SIZE))
+                if needed == 2 * MAX_ENDPOINT_SIZE + "/ingress/workloads//key/".len()
+        ));
+    }
+}
@@ -1,19 +1,22 @@
-use kbs_types::{Response as KbsResponse, SnpAttestation, Tee};
+use kbs_types::{SnpAttestation, Tee};
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 
 use crate::{
-    client_proxy::{
-        Error as CPError, HttpMethod, Proxy, ProxyRequest, Request, RequestType, Response,
+    client_proxy::{Connection, Error as CPError, Proxy, ProxyRequest, Request},
+    client_registration::{Error as CRError, TeeRegistration},
+    client_session::{
+        Attestation, Error as CSError, ProtocolVersion, Request as KbsRequest, TeeSession,
     },
-    client_registration::TeeRegistration,
-    client_session::{Error as CSError, TeeSession},
-    clients::SnpGeneration,
+    clients::{send, Endpoints, SnpGeneration},
     lib::{String, ToString, Vec},
+    secret::{Secret, SecretBytes, SecretString},
 };
 
 pub struct KeybrokerClientSnp {
     attestation: SnpAttestation,
+    versions: &'static [ProtocolVersion],
+    endpoints: Endpoints,
 }
 
 impl KeybrokerClientSnp {
@@ -24,19 +27,41 @@ impl KeybrokerClientSnp {
                 cert_chain: "".to_string(),
                 gen: gen.to_string(),
             },
+            versions: &[ProtocolVersion::V0_1_0],
+            endpoints: Endpoints::new("/resource"),
         }
     }
 
+    /// Sends the requests to `endpoints` instead of the default ones.
+    pub fn with_endpoints(mut self, endpoints: Endpoints) -> Self {
+        self.endpoints = endpoints;
+        self
+    }
+
+    /// Advertises `versions` of the protocol, newest first, instead of only
+    /// the `0.1.0` version of the server.
+    pub fn with_versions(mut self, versions: &'static [ProtocolVersion]) -> Self {
+        self.versions = versions;
+        self
+    }
+
     pub fn update_report(&mut self, report: &[u8]) {
         self.attestation.report = hex::encode(report);
     }
-}
 
-impl TeeSession for KeybrokerClientSnp {
-    fn version(&self) -> String {
-        "0.1.0".to_string()
+    fn path(&self, template: &str) -> Result<String, CPError> {
+        self.endpoints.path(template, None)
     }
+}
+
+/// Response of keybroker (`kbs_types::Response`), of which only the
+/// ciphertext is needed.
+#[derive(Deserialize)]
+struct SecretResponse<'a> {
+    ciphertext: &'a str,
+}
 
+impl TeeSession for KeybrokerClientSnp {
     fn tee(&self) -> Tee {
         Tee::Snp
     }
@@ -49,51 +74,48 @@ impl TeeSession for KeybrokerClientSnp {
         json!(self.attestation)
     }
 
-    fn secret(&self, data: String) -> Result<String, CSError> {
-        let resp: KbsResponse = serde_json::from_str(&data)?;
+    fn versions(&self) -> &[ProtocolVersion] {
+        self.versions
+    }
+
+    fn secret<'d>(&self, data: &'d [u8]) -> Result<&'d str, CSError> {
+        let resp: SecretResponse = serde_json::from_slice(data)?;
 
         Ok(resp.ciphertext)
     }
 }
 
 impl ProxyRequest for KeybrokerClientSnp {
-    fn make(
+    fn auth<C: Connection>(
         &self,
-        proxy: &mut Proxy,
-        req_type: RequestType,
-        body: Option<&Value>,
-    ) -> Result<Option<String>, CPError> {
-        let req = match req_type {
-            RequestType::Auth => Request {
-                endpoint: "/kbs/v0/auth".to_string(),
-                method: HttpMethod::POST,
-                body: json!(&body.ok_or(CPError::BodyExpected(req_type))?),
-            },
-            RequestType::Attest => Request {
-                endpoint: "/kbs/v0/attest".to_string(),
-                method: HttpMethod::POST,
-                body: json!(&body.ok_or(CPError::BodyExpected(req_type))?),
-            },
-            RequestType::Key => Request {
-                endpoint: "/kbs/v0/resource".to_string(),
-                method: HttpMethod::GET,
-                body: json!(""),
-            },
-        };
+        proxy: &mut Proxy<C>,
+        request: &KbsRequest,
+    ) -> Result<Vec<u8>, CPError> {
+        let req = Request::post(
+            self.path(&self.endpoints.auth)?,
+            serde_json::to_value(request)?,
+        );
+
+        send(proxy, &req)?.body_bytes()
+    }
 
-        proxy.write_json(&json!(req))?;
-        let data = proxy.read_json()?;
-        let resp: Response = serde_json::from_value(data)?;
+    fn attest<C: Connection>(
+        &self,
+        proxy: &mut Proxy<C>,
+        attestation: &Attestation,
+    ) -> Result<(), CPError> {
+        let req = Request::post(
+            self.path(&self.endpoints.attest)?,
+            serde_json::to_value(attestation)?,
+        );
 
-        if !resp.is_success() {
-            return Err(CPError::HttpError(resp.status, resp.body));
-        }
+        send(proxy, &req).map(|_| ())
+    }
 
-        match req_type {
-            RequestType::Auth => Ok(Some(resp.body)),
-            RequestType::Attest => Ok(None),
-            RequestType::Key => Ok(Some(resp.body)),
-        }
+    fn key<C: Connection>(&self, proxy: &mut Proxy<C>) -> Result<SecretBytes, CPError> {
+        let req = Request::get(self.path(&self.endpoints.key)?);
+
+        send(proxy, &req)?.into_secret_body()
     }
 }
 
@@ -113,17 +135,18 @@ pub struct Workload {
     policy: String,
     queries: Vec<String>,
     reference: String,
-    resources: String,
+    #[serde(serialize_with = "Secret::serialize_exposed")]
+    resources: SecretString,
 }
 
 impl TeeRegistration for KeybrokerRegistration {
-    fn register(&self, measurement: &[u8], secret: String) -> Value {
-        json!(Workload {
+    fn register(&self, measurement: &[u8], secret: SecretString) -> Result<Value, CRError> {
+        Ok(serde_json::to_value(Workload {
             policy: self.policy.clone(),
             queries: self.queries.clone(),
             reference: json!({"measurement": hex::encode(measurement)}).to_string(),
             resources: secret,
-        })
+        })?)
     }
 }
 
@@ -131,10 +154,11 @@ impl TeeRegistration for KeybrokerRegistration {
 mod tests {
     use core::str::FromStr;
 
+    use kbs_types::Response as KbsResponse;
     use num_bigint::BigUint;
 
     use super::*;
-    use crate::{client_registration::*, client_session::*};
+    use crate::{client_registration::ClientRegistration, client_session::*};
 
     #[test]
     fn test_session() {
@@ -144,7 +168,7 @@ mod tests {
 
         let request = cs.request(&snp).unwrap();
         assert_eq!(
-            request,
+            json!(request),
             json!({
                 "version": "0.1.0",
                 "tee": "snp",
@@ -152,14 +176,12 @@ mod tests {
             }),
         );
 
-        let challenge = r#"
+        let challenge = br#"
         {
             "nonce": "424242",
             "extra-params": ""
         }"#;
-        let nonce = cs
-            .challenge(serde_json::from_str(challenge).unwrap())
-            .unwrap();
+        let nonce = cs.challenge(challenge).unwrap();
         assert_eq!(nonce, "424242".to_string());
 
         let report = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9];
@@ -174,7 +196,7 @@ mod tests {
             .attestation(k_mod_encoded.clone(), k_exp_encoded.clone(), &snp)
             .unwrap();
         assert_eq!(
-            attestation,
+            json!(attestation),
             json!({
                 "tee-pubkey": json!({
                     "alg": "RSA",
@@ -202,8 +224,120 @@ mod tests {
 
             json!(resp)
         };
-        let secret = cs.secret(data.to_string(), &snp).unwrap();
-        assert_eq!(secret, remote_secret);
+        let secret = cs.secret(data.to_string().as_bytes(), &snp).unwrap();
+        assert_eq!(secret.expose_secret(), &remote_secret);
+    }
+
+    #[test]
+    fn test_session_v0_2_0() {
+        let mut snp =
+            KeybrokerClientSnp::new(SnpGeneration::Milan).with_versions(ProtocolVersion::SUPPORTED);
+
+        let mut cs = ClientSession::new();
+
+        let request = cs.request(&snp).unwrap();
+        assert_eq!(
+            json!(request),
+            json!({
+                "version": "0.2.0",
+                "tee": "snp",
+                "extra-params": null,
+            }),
+        );
+
+        // The nonce is part of the attestation
+        assert!(matches!(
+            cs.attestation("mod".to_string(), "exp".to_string(), &snp),
+            Err(CSError::ChallengeExpected)
+        ));
+
+        let challenge = br#"{"nonce": "424242", "extra-params": {}}"#;
+        assert_eq!(cs.challenge(challenge).unwrap(), "424242");
+
+        let report = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9];
+        snp.update_report(&report);
+
+        let attestation = cs
+            .attestation("mod".to_string(), "exp".to_string(), &snp)
+            .unwrap();
+        assert_eq!(
+            json!(attestation),
+            json!({
+                "runtime-data": {
+                    "nonce": "424242",
+                    "tee-pubkey": {"kty": "RSA", "alg": "RSA1_5", "n": "mod", "e": "exp"},
+                },
+                "tee-evidence": {
+                    "cert_chain": "",
+                    "gen": "milan",
+                    "report": hex::encode(report),
+                },
+            }),
+        );
+
+        assert_eq!(cs.fallback(&snp).unwrap(), ProtocolVersion::V0_1_0);
+        assert_eq!(json!(cs.request(&snp).unwrap())["version"], "0.1.0");
+        assert!(matches!(
+            cs.fallback(&snp),
+            Err(CSError::UnsupportedVersion)
+        ));
+    }
+
+    #[cfg(feature = "jws")]
+    #[test]
+    fn test_session_pinned() {
+        use crate::jws::{Jws, SigningKey};
+
+        let snp = KeybrokerClientSnp::new(SnpGeneration::Milan);
+        let kbs_key = SigningKey::from_bytes(&[3u8; 32]);
+        let mut cs = ClientSession::with_server_key(kbs_key.verifying_key());
+
+        let challenge = br#"{"nonce": "424242", "extra-params": ""}"#;
+        assert!(matches!(
+            cs.challenge(challenge),
+            Err(CSError::JsonError(_))
+        ));
+
+        let signed = serde_json::to_vec(&Jws::sign(challenge, &kbs_key).unwrap()).unwrap();
+        assert_eq!(cs.challenge(&signed).unwrap(), "424242");
+
+        // A secret signed by someone else is rejected
+        let remote_secret = [9, 8, 7, 6, 5, 4, 3, 2, 1, 0];
+        let data = json!(KbsResponse {
+            protected: "".to_string(),
+            encrypted_key: "".to_string(),
+            iv: "".to_string(),
+            ciphertext: hex::encode(remote_secret),
+            tag: "".to_string(),
+        })
+        .to_string();
+
+        let forged = Jws::sign(data.as_bytes(), &SigningKey::from_bytes(&[4u8; 32])).unwrap();
+        assert!(matches!(
+            cs.secret(json!(forged).to_string().as_bytes(), &snp),
+            Err(CSError::JwsError(_))
+        ));
+
+        let signed = Jws::sign(data.as_bytes(), &kbs_key).unwrap();
+        let secret = cs
+            .secret(json!(signed).to_string().as_bytes(), &snp)
+            .unwrap();
+        assert_eq!(secret.expose_secret(), &remote_secret);
+    }
+
+    #[test]
+    fn test_endpoints() {
+        let snp = KeybrokerClientSnp::new(SnpGeneration::Milan).with_endpoints(Endpoints {
+            key: "/key/{workload_id}".to_string(),
+            ..Endpoints::new("/resource").base("/ingress")
+        });
+        assert_eq!(snp.path(&snp.endpoints.auth).unwrap(), "/ingress/auth");
+
+        // keybroker has no workload ID to expand
+        assert!(matches!(
+            snp.path(&snp.endpoints.key),
+            Err(CPError::MissingWorkloadId)
+        ));
     }
 
     #[test]
@@ -211,9 +345,10 @@ mod tests {
         let kr = KeybrokerRegistration::new("my_policy".to_string(), vec!["my_query1".to_string()]);
         let registration = ClientRegistration::register(
             &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9],
-            "secret".to_string(),
+            SecretString::new("secret".to_string()),
             &kr,
-        );
+        )
+        .unwrap();
         assert_eq!(
             registration,
             json!({
@@ -3,18 +3,21 @@ use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 
 use crate::{
-    client_proxy::{
-        Error as CPError, HttpMethod, Proxy, ProxyRequest, Request, RequestType, Response,
+    client_proxy::{Connection, Error as CPError, Proxy, ProxyRequest, Request},
+    client_registration::{Error as CRError, TeeRegistration},
+    client_session::{
+        Attestation, Error as CSError, ProtocolVersion, Request as KbsRequest, Tee, TeeSession,
     },
-    client_registration::TeeRegistration,
-    client_session::{Error as CSError, Tee, TeeSession},
-    clients::SnpGeneration,
-    lib::{String, ToString},
+    clients::{send, Endpoints, SnpGeneration},
+    lib::{String, ToString, Vec},
+    secret::{Secret, SecretBytes, SecretString},
 };
 
 pub struct ReferenceKBSClientSnp {
     request: SnpRequest,
     attestation: SnpAttestation,
+    versions: &'static [ProtocolVersion],
+    endpoints: Endpoints,
 }
 
 impl ReferenceKBSClientSnp {
@@ -26,19 +29,35 @@ impl ReferenceKBSClientSnp {
                 cert_chain: "".to_string(),
                 gen: gen.to_string(),
             },
+            versions: &[ProtocolVersion::V0_1_0],
+            endpoints: Endpoints::new("/key/{workload_id}"),
         }
     }
 
+    /// Sends the requests to `endpoints` instead of the default ones.
+    pub fn with_endpoints(mut self, endpoints: Endpoints) -> Self {
+        self.endpoints = endpoints;
+        self
+    }
+
+    /// Advertises `versions` of the protocol, newest first, instead of only
+    /// the `0.1.0` version of the server.
+    pub fn with_versions(mut self, versions: &'static [ProtocolVersion]) -> Self {
+        self.versions = versions;
+        self
+    }
+
     pub fn update_report(&mut self, report: &[u8]) {
         self.attestation.report = hex::encode(report);
     }
-}
 
-impl TeeSession for ReferenceKBSClientSnp {
-    fn version(&self) -> String {
-        "0.1.0".to_string()
+    fn path(&self, template: &str) -> Result<String, CPError> {
+        self.endpoints
+            .path(template, Some(&self.request.workload_id))
     }
+}
 
+impl TeeSession for ReferenceKBSClientSnp {
     fn tee(&self) -> Tee {
         Tee::Snp
     }
@@ -51,49 +70,46 @@ impl TeeSession for ReferenceKBSClientSnp {
         json!(self.attestation)
     }
 
-    fn secret(&self, data: String) -> Result<String, CSError> {
-        Ok(serde_json::from_str(&data)?)
+    fn versions(&self) -> &[ProtocolVersion] {
+        self.versions
+    }
+
+    fn secret<'d>(&self, data: &'d [u8]) -> Result<&'d str, CSError> {
+        Ok(serde_json::from_slice(data)?)
     }
 }
 
 impl ProxyRequest for ReferenceKBSClientSnp {
-    fn make(
+    fn auth<C: Connection>(
         &self,
-        proxy: &mut Proxy,
-        req_type: RequestType,
-        body: Option<&Value>,
-    ) -> Result<Option<String>, CPError> {
-        let req = match req_type {
-            RequestType::Auth => Request {
-                endpoint: "/kbs/v0/auth".to_string(),
-                method: HttpMethod::POST,
-                body: json!(&body.ok_or(CPError::BodyExpected(req_type))?),
-            },
-            RequestType::Attest => Request {
-                endpoint: "/kbs/v0/attest".to_string(),
-                method: HttpMethod::POST,
-                body: json!(&body.ok_or(CPError::BodyExpected(req_type))?),
-            },
-            RequestType::Key => Request {
-                endpoint: "/kbs/v0/key/".to_string() + &self.request.workload_id,
-                method: HttpMethod::GET,
-                body: json!(""),
-            },
-        };
+        proxy: &mut Proxy<C>,
+        request: &KbsRequest,
+    ) -> Result<Vec<u8>, CPError> {
+        let req = Request::post(
+            self.path(&self.endpoints.auth)?,
+            serde_json::to_value(request)?,
+        );
+
+        send(proxy, &req)?.body_bytes()
+    }
 
-        proxy.write_json(&json!(req))?;
-        let data = proxy.read_json()?;
-        let resp: Response = serde_json::from_value(data)?;
+    fn attest<C: Connection>(
+        &self,
+        proxy: &mut Proxy<C>,
+        attestation: &Attestation,
+    ) -> Result<(), CPError> {
+        let req = Request::post(
+            self.path(&self.endpoints.attest)?,
+            serde_json::to_value(attestation)?,
+        );
 
-        if !resp.is_success() {
-            return Err(CPError::HttpError(resp.status, resp.body));
-        }
+        send(proxy, &req).map(|_| ())
+    }
 
-        match req_type {
-            RequestType::Auth => Ok(Some(resp.body)),
-            RequestType::Attest => Ok(None),
-            RequestType::Key => Ok(Some(resp.body)),
-        }
+    fn key<C: Connection>(&self, proxy: &mut Proxy<C>) -> Result<SecretBytes, CPError> {
+        let req = Request::get(self.path(&self.endpoints.key)?);
+
+        send(proxy, &req)?.into_secret_body()
     }
 }
 
@@ -112,17 +128,18 @@ struct Workload {
     workload_id: String,
     launch_measurement: String,
     tee_config: String,
-    passphrase: String,
+    #[serde(serialize_with = "Secret::serialize_exposed")]
+    passphrase: SecretString,
 }
 
 impl TeeRegistration for ReferenceKBSRegistration {
-    fn register(&self, measurement: &[u8], secret: String) -> Value {
-        json!(Workload {
+    fn register(&self, measurement: &[u8], secret: SecretString) -> Result<Value, CRError> {
+        Ok(serde_json::to_value(Workload {
             workload_id: self.workload_id.clone(),
             launch_measurement: hex::encode(measurement),
             tee_config: "".to_string(),
             passphrase: secret,
-        })
+        })?)
     }
 }
 
@@ -130,10 +147,11 @@ impl TeeRegistration for ReferenceKBSRegistration {
 mod tests {
     use core::str::FromStr;
 
+    use base64ct::{Base64, Encoding};
     use num_bigint::BigUint;
 
     use super::*;
-    use crate::{client_registration::*, client_session::*};
+    use crate::{client_registration::ClientRegistration, client_session::*};
 
     #[test]
     fn test_session() {
@@ -143,7 +161,7 @@ mod tests {
 
         let request = cs.request(&snp).unwrap();
         assert_eq!(
-            request,
+            json!(request),
             json!({
                 "version": "0.1.0",
                 "tee": "snp",
@@ -151,14 +169,12 @@ mod tests {
             }),
         );
 
-        let challenge = r#"
+        let challenge = br#"
         {
             "nonce": "424242",
             "extra-params": ""
         }"#;
-        let nonce = cs
-            .challenge(serde_json::from_str(challenge).unwrap())
-            .unwrap();
+        let nonce = cs.challenge(challenge).unwrap();
         assert_eq!(nonce, "424242".to_string());
 
         let report = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9];
@@ -173,7 +189,7 @@ mod tests {
             .attestation(k_mod_encoded.clone(), k_exp_encoded.clone(), &snp)
             .unwrap();
         assert_eq!(
-            attestation,
+            json!(attestation),
             json!({
                 "tee-pubkey": json!({
                     "alg": "RSA",
@@ -188,11 +204,145 @@ mod tests {
                 }).to_string(),
             }),
         );
+        assert_eq!(
+            json!(Embedded::new(snp.evidence(), JsonEmbedding::Object)),
+            snp.evidence(),
+        );
 
         let remote_secret = [9, 8, 7, 6, 5, 4, 3, 2, 1, 0];
-        let data = json!(hex::encode(remote_secret));
-        let secret = cs.secret(data.to_string(), &snp).unwrap();
-        assert_eq!(secret, remote_secret);
+        let data = json!(hex::encode(remote_secret)).to_string();
+        let secret = cs.secret(data.as_bytes(), &snp).unwrap();
+        assert_eq!(secret.expose_secret(), &remote_secret);
+
+        let mut buf = [0xffu8; 16];
+        let len = cs.secret_into(data.as_bytes(), &snp, &mut buf).unwrap();
+        assert_eq!(&buf[..len], &remote_secret);
+
+        let mut small = [0xffu8; 4];
+        assert!(matches!(
+            cs.secret_into(data.as_bytes(), &snp, &mut small),
+            Err(Error::BufferTooSmall(10, 4))
+        ));
+        assert_eq!(small, [0; 4]);
+
+        let encoded = Base64::encode_string(&remote_secret);
+        let len = SecretEncoding::Base64
+            .decode_into(&encoded, &mut buf)
+            .unwrap();
+        assert_eq!(&buf[..len], &remote_secret);
+        assert!(matches!(
+            SecretEncoding::Base64.decode_into(&encoded, &mut small),
+            Err(Error::BufferTooSmall(_, 4))
+        ));
+    }
+
+    #[test]
+    fn test_proxy_request() {
+        use std::{os::unix::net::UnixStream, thread};
+
+        use crate::client_proxy::{
+            unix::UnixConnection, Request as ProxyMessage, Response, SUPPORTED_CAPABILITIES,
+        };
+
+        let (guest, host) = UnixStream::pair().unwrap();
+
+        let kbs = thread::spawn(move || {
+            let mut proxy = Proxy::new(UnixConnection(host));
+            proxy.accept_handshake(SUPPORTED_CAPABILITIES).unwrap();
+
+            // Only the 0.1.0 version is supported
+            let req: ProxyMessage = proxy.read_message().unwrap();
+            assert_eq!(req.endpoint, "/ingress/kbs/auth");
+            assert_eq!(req.body["version"], "0.2.0");
+            let mut resp = Response::new(401, "Unsupported version".to_string());
+            resp.id = req.id;
+            proxy.write_message(&resp).unwrap();
+
+            let req: ProxyMessage = proxy.read_message().unwrap();
+            assert_eq!(req.endpoint, "/ingress/kbs/auth");
+            assert_eq!(req.body["version"], "0.1.0");
+            assert_eq!(req.body["tee"], "snp");
+            let mut resp =
+                Response::new(200, json!({"nonce": "42", "extra-params": ""}).to_string());
+            resp.id = req.id;
+            proxy.write_message(&resp).unwrap();
+
+            let req: ProxyMessage = proxy.read_message().unwrap();
+            assert_eq!(req.endpoint, "/ingress/kbs/attest");
+            assert_eq!(req.body["tee-pubkey"]["n"], "mod");
+            let mut resp = Response::new(401, "Unauthorized".to_string());
+            resp.id = req.id;
+            proxy.write_message(&resp).unwrap();
+        });
+
+        let mut proxy = Proxy::new(UnixConnection(guest));
+        proxy.handshake(SUPPORTED_CAPABILITIES).unwrap();
+
+        let snp = ReferenceKBSClientSnp::new(SnpGeneration::Milan, "snp-workload".to_string())
+            .with_versions(ProtocolVersion::SUPPORTED)
+            .with_endpoints(Endpoints::new("/key/{workload_id}").base("/ingress/kbs"));
+        let mut cs = ClientSession::new();
+
+        let challenge = snp.negotiate(&mut proxy, &mut cs).unwrap();
+        assert_eq!(cs.version(), Some(ProtocolVersion::V0_1_0));
+        assert_eq!(cs.challenge(&challenge).unwrap(), "42");
+
+        let attestation = cs
+            .attestation("mod".to_string(), "exp".to_string(), &snp)
+            .unwrap();
+        assert!(matches!(
+            snp.attest(&mut proxy, &attestation),
+            Err(CPError::HttpError(401, _))
+        ));
+
+        kbs.join().unwrap();
+    }
+
+    #[test]
+    fn test_negotiate_rejected() {
+        use std::{os::unix::net::UnixStream, thread};
+
+        use crate::client_proxy::{
+            unix::UnixConnection, Request as ProxyMessage, Response, SUPPORTED_CAPABILITIES,
+        };
+
+        let (guest, host) = UnixStream::pair().unwrap();
+
+        let kbs = thread::spawn(move || {
+            let mut proxy = Proxy::new(UnixConnection(host));
+            proxy.accept_handshake(SUPPORTED_CAPABILITIES).unwrap();
+
+            // Every version is rejected, then an error unrelated to the version
+            for (version, status) in [("0.2.0", 401), ("0.1.0", 401), ("0.2.0", 404)] {
+                let req: ProxyMessage = proxy.read_message().unwrap();
+                assert_eq!(req.body["version"], version);
+                let mut resp = Response::new(status, "Rejected".to_string());
+                resp.id = req.id;
+                proxy.write_message(&resp).unwrap();
+            }
+        });
+
+        let mut proxy = Proxy::new(UnixConnection(guest));
+        proxy.handshake(SUPPORTED_CAPABILITIES).unwrap();
+
+        let snp = ReferenceKBSClientSnp::new(SnpGeneration::Milan, "snp-workload".to_string())
+            .with_versions(ProtocolVersion::SUPPORTED);
+
+        let mut cs = ClientSession::new();
+        assert!(matches!(
+            snp.negotiate(&mut proxy, &mut cs),
+            Err(crate::Error::CP(CPError::HttpError(401, _)))
+        ));
+        assert_eq!(cs.version(), Some(ProtocolVersion::V0_1_0));
+
+        let mut cs = ClientSession::new();
+        assert!(matches!(
+            snp.negotiate(&mut proxy, &mut cs),
+            Err(crate::Error::CP(CPError::HttpError(404, _)))
+        ));
+        assert_eq!(cs.version(), Some(ProtocolVersion::V0_2_0));
+
+        kbs.join().unwrap();
     }
 
     #[test]
@@ -200,9 +350,10 @@ mod tests {
         let rkr = ReferenceKBSRegistration::new("snp-workload".to_string());
         let registration = ClientRegistration::register(
             &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9],
-            "secret".to_string(),
+            SecretString::new("secret".to_string()),
             &rkr,
-        );
+        )
+        .unwrap();
         assert_eq!(
             registration,
             json!({
@@ -0,0 +1,190 @@
+//! Verification of KBS responses signed with a pinned key.
+//!
+//! Signed responses use the flattened JSON serialization of JWS (RFC 7515)
+//! with the EdDSA algorithm (Ed25519, RFC 8037), so they are still JSON
+//! documents carried as the body of the KBS response:
+//!
+//! ```json
+//! {"protected": "<header>", "payload": "<response>", "signature": "<sig>"}
+//! ```
+//!
+//! where each field is base64url encoded without padding, and the payload is
+//! the response the KBS would return unsigned.
+
+use base64ct::{Base64UrlUnpadded, Encoding};
+use ed25519_dalek::{Signature, Signer};
+pub use ed25519_dalek::{SigningKey, VerifyingKey, PUBLIC_KEY_LENGTH};
+use serde::{Deserialize, Serialize};
+
+use crate::lib::{fmt, Debug, String, ToString, Vec};
+
+/// The only algorithm accepted in the protected header.
+pub const ALG_EDDSA: &str = "EdDSA";
+
+#[derive(Debug)]
+pub enum Error {
+    JsonError(serde_json::Error),
+    Base64Error(base64ct::Error),
+    InvalidKey,
+    UnsupportedAlgorithm(String),
+    BadSignature,
+}
+
+impl Error {
+    /// Stable code of the error, see [`crate::Error::code`].
+    pub fn code(&self) -> u16 {
+        match self {
+            Self::JsonError(_) => 1,
+            Self::Base64Error(_) => 2,
+            Self::InvalidKey => 3,
+            Self::UnsupportedAlgorithm(_) => 4,
+            Self::BadSignature => 5,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::JsonError(e) => Some(e),
+            Self::Base64Error(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::JsonError(je) => write!(f, "Malformed JWS - {je}"),
+            Self::Base64Error(be) => write!(f, "Malformed JWS encoding - {be}"),
+            Self::InvalidKey => write!(f, "Invalid Ed25519 public key"),
+            Self::UnsupportedAlgorithm(alg) => write!(f, "Unsupported JWS algorithm: {alg}"),
+            Self::BadSignature => write!(f, "JWS signature verification failed"),
+        }
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Self::JsonError(e)
+    }
+}
+
+impl From<base64ct::Error> for Error {
+    fn from(e: base64ct::Error) -> Self {
+        Self::Base64Error(e)
+    }
+}
+
+impl From<Error> for crate::Error {
+    fn from(e: Error) -> Self {
+        Self::JWS(e)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    alg: String,
+}
+
+/// JWS in flattened JSON serialization.
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct Jws {
+    pub protected: String,
+    pub payload: String,
+    pub signature: String,
+}
+
+/// Parses a raw Ed25519 public key, e.g. the one pinned in the firmware.
+pub fn verifying_key(bytes: &[u8; PUBLIC_KEY_LENGTH]) -> Result<VerifyingKey, Error> {
+    VerifyingKey::from_bytes(bytes).map_err(|_| Error::InvalidKey)
+}
+
+fn signing_input(protected: &str, payload: &str) -> Vec<u8> {
+    let mut input = Vec::with_capacity(protected.len() + 1 + payload.len());
+    input.extend_from_slice(protected.as_bytes());
+    input.push(b'.');
+    input.extend_from_slice(payload.as_bytes());
+    input
+}
+
+impl Jws {
+    /// Signs `payload` with `key`, as done by the KBS.
+    pub fn sign(payload: &[u8], key: &SigningKey) -> Result<Self, Error> {
+        let header = serde_json::to_vec(&Header {
+            alg: ALG_EDDSA.to_string(),
+        })?;
+        let protected = Base64UrlUnpadded::encode_string(&header);
+        let payload = Base64UrlUnpadded::encode_string(payload);
+
+        let signature = key.sign(&signing_input(&protected, &payload));
+
+        Ok(Jws {
+            protected,
+            payload,
+            signature: Base64UrlUnpadded::encode_string(&signature.to_bytes()),
+        })
+    }
+
+    /// Verifies the signature with `key` and returns the decoded payload.
+    pub fn verify(&self, key: &VerifyingKey) -> Result<Vec<u8>, Error> {
+        let header: Header =
+            serde_json::from_slice(&Base64UrlUnpadded::decode_vec(&self.protected)?)?;
+        if header.alg != ALG_EDDSA {
+            return Err(Error::UnsupportedAlgorithm(header.alg));
+        }
+
+        let mut signature = [0u8; Signature::BYTE_SIZE];
+        let decoded = Base64UrlUnpadded::decode(&self.signature, &mut signature)?;
+        if decoded.len() != Signature::BYTE_SIZE {
+            return Err(Error::BadSignature);
+        }
+
+        // Strict verification rejects malleable signatures and weak keys
+        key.verify_strict(
+            &signing_input(&self.protected, &self.payload),
+            &Signature::from_bytes(&signature),
+        )
+        .map_err(|_| Error::BadSignature)?;
+
+        Ok(Base64UrlUnpadded::decode_vec(&self.payload)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_jws() {
+        let key = SigningKey::from_bytes(&[1u8; 32]);
+        let pinned = verifying_key(key.verifying_key().as_bytes()).unwrap();
+
+        let jws = Jws::sign(b"{\"nonce\":\"42\"}", &key).unwrap();
+        assert_eq!(jws.verify(&pinned).unwrap(), b"{\"nonce\":\"42\"}");
+
+        // Round trip through JSON, as carried in the response body
+        let jws: Jws = serde_json::from_str(&serde_json::to_string(&jws).unwrap()).unwrap();
+        assert!(jws.verify(&pinned).is_ok());
+
+        // Another key
+        let other = SigningKey::from_bytes(&[2u8; 32]);
+        let forged = Jws::sign(b"{\"nonce\":\"42\"}", &other).unwrap();
+        assert!(matches!(forged.verify(&pinned), Err(Error::BadSignature)));
+
+        // Tampered payload
+        let mut tampered = jws.clone();
+        tampered.payload = Base64UrlUnpadded::encode_string(b"{\"nonce\":\"43\"}");
+        assert!(matches!(tampered.verify(&pinned), Err(Error::BadSignature)));
+
+        // Algorithm downgrade
+        let mut none = jws;
+        none.protected = Base64UrlUnpadded::encode_string(b"{\"alg\":\"none\"}");
+        assert!(matches!(
+            none.verify(&pinned),
+            Err(Error::UnsupportedAlgorithm(_))
+        ));
+    }
+}
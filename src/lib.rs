@@ -4,6 +4,9 @@
 // without the rest of the standard library by using the `alloc` crate
 #[cfg(feature = "alloc")]
 extern crate alloc;
+// Without a memory allocator, the `heapless` feature provides fixed-capacity
+// versions of the proxy (`client_proxy::heapless`), of the session
+// (`client_session::heapless`) and of the SNP clients (`clients::heapless`)
 
 /// A facade around all the types we need from the `std`, `core`, and `alloc`
 /// crates. This avoids elaborate import wrangling having to happen in every
@@ -25,40 +28,146 @@ mod lib {
     }
 
     // alloc modules (re-exported by `std` when have the standard library)
+    #[cfg(feature = "alloc")]
     pub use self::alloc::{
         boxed::Box,
+        collections::BTreeMap,
         string::{String, ToString},
         vec,
         vec::Vec,
     };
+    // only needed by the modules that require an allocator
+    #[cfg(feature = "alloc")]
+    pub use self::core::mem;
     // core modules (re-exported by `std` when have the standard library)
     pub use self::core::{
         fmt::{self, Debug, Display},
         num::TryFromIntError,
+        time::Duration,
     };
 }
 
 pub mod client_proxy;
+#[cfg(feature = "alloc")]
 pub mod client_registration;
 pub mod client_session;
 pub mod clients;
+#[cfg(feature = "jws")]
+pub mod jws;
+#[cfg(feature = "alloc")]
+pub mod secret;
+#[cfg(feature = "secure_channel")]
+pub mod secure_channel;
 
+/// Errors returned by this crate.
+///
+/// Each error has a stable numeric code (see [`Error::code`]) that host
+/// tooling and firmware can report without parsing messages: the hundreds
+/// identify the module, the rest the variant of the module error.
+///
+/// | Codes   | Module                   |
+/// |---------|--------------------------|
+/// | 100-199 | [`client_session`]       |
+/// | 200-299 | [`client_proxy`]         |
+/// | 300-399 | [`client_registration`]  |
+/// | 400-499 | `secure_channel`         |
+/// | 500-599 | `jws`                    |
+///
+/// With the `std` feature, [`std::error::Error::source`] returns the module
+/// error, which in turn returns the error of the underlying library or
+/// transport, if any.
 #[derive(Debug)]
 pub enum Error {
     // Errors related to client_session
     CS(client_session::Error),
     // Errors related to client_proxy
     CP(client_proxy::Error),
+    // Errors related to client_registration
+    #[cfg(feature = "alloc")]
+    CR(client_registration::Error),
+    // Errors related to secure_channel
+    #[cfg(feature = "secure_channel")]
+    SC(secure_channel::Error),
+    // Errors related to jws
+    #[cfg(feature = "jws")]
+    JWS(jws::Error),
+}
+
+impl Error {
+    /// Stable code identifying the error.
+    pub fn code(&self) -> u16 {
+        match self {
+            Self::CS(e) => 100 + e.code(),
+            Self::CP(e) => 200 + e.code(),
+            #[cfg(feature = "alloc")]
+            Self::CR(e) => 300 + e.code(),
+            #[cfg(feature = "secure_channel")]
+            Self::SC(e) => 400 + e.code(),
+            #[cfg(feature = "jws")]
+            Self::JWS(e) => 500 + e.code(),
+        }
+    }
 }
 
 #[cfg(feature = "std")]
-impl std::error::Error for Error {}
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::CS(e) => Some(e),
+            Self::CP(e) => Some(e),
+            #[cfg(feature = "alloc")]
+            Self::CR(e) => Some(e),
+            #[cfg(feature = "secure_channel")]
+            Self::SC(e) => Some(e),
+            #[cfg(feature = "jws")]
+            Self::JWS(e) => Some(e),
+        }
+    }
+}
 
 impl lib::fmt::Display for Error {
     fn fmt(&self, f: &mut lib::fmt::Formatter<'_>) -> lib::fmt::Result {
         match self {
             Self::CS(e) => write!(f, "Session error: {e}"),
             Self::CP(e) => write!(f, "Proxy error: {e}"),
+            #[cfg(feature = "alloc")]
+            Self::CR(e) => write!(f, "Registration error: {e}"),
+            #[cfg(feature = "secure_channel")]
+            Self::SC(e) => write!(f, "Secure channel error: {e}"),
+            #[cfg(feature = "jws")]
+            Self::JWS(e) => write!(f, "JWS error: {e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_codes() {
+        let e = Error::from(client_proxy::Error::Timeout);
+        assert_eq!(e.code(), 220);
+        assert_eq!(e.to_string(), "Proxy error: Timed out waiting for the peer");
+
+        let e = Error::from(client_session::Error::BufferTooSmall(10, 4));
+        assert_eq!(e.code(), 104);
+
+        let json = serde_json::from_str::<u32>("x").unwrap_err();
+        let e = Error::from(client_registration::Error::from(json));
+        assert_eq!(e.code(), 301);
+
+        #[cfg(feature = "std")]
+        {
+            use std::error::Error as _;
+
+            let io = std::io::Error::from_raw_os_error(104);
+            let e = Error::from(client_proxy::Error::ReadError(io.into()));
+            let cp = e.source().unwrap();
+            assert!(cp.to_string().starts_with("Read failed"));
+            let io = cp.source().unwrap();
+            assert_eq!(io.to_string(), "connection reset (os error 104)");
+            assert!(io.source().is_none());
         }
     }
 }
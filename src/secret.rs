@@ -0,0 +1,125 @@
+//! Wrapper for sensitive data (keys, passphrases, resources).
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use zeroize::{Zeroize, ZeroizeOnDrop};
+
+use crate::lib::{fmt, Debug, Display, String, Vec};
+
+/// Holds sensitive data, which is zeroed when dropped and never printed.
+///
+/// The data is only reachable through [`Secret::expose_secret`], so every
+/// place where it leaves the wrapper is explicit.
+pub struct Secret<T: Zeroize>(T);
+
+pub type SecretString = Secret<String>;
+pub type SecretBytes = Secret<Vec<u8>>;
+
+impl<T: Zeroize> Secret<T> {
+    pub fn new(value: T) -> Self {
+        Secret(value)
+    }
+
+    pub fn expose_secret(&self) -> &T {
+        &self.0
+    }
+
+    /// Serializes the exposed secret, to be used with
+    /// `#[serde(serialize_with = "Secret::serialize_exposed")]` where the
+    /// secret must be sent (e.g. when registering it with the KBS).
+    pub fn serialize_exposed<S: Serializer>(secret: &Self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        T: Serialize,
+    {
+        secret.0.serialize(serializer)
+    }
+}
+
+impl<T: Zeroize> Drop for Secret<T> {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl<T: Zeroize> ZeroizeOnDrop for Secret<T> {}
+
+impl<T: Zeroize> From<T> for Secret<T> {
+    fn from(value: T) -> Self {
+        Secret(value)
+    }
+}
+
+impl<T: Zeroize + Clone> Clone for Secret<T> {
+    fn clone(&self) -> Self {
+        Secret(self.0.clone())
+    }
+}
+
+impl<T: Zeroize> Debug for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Secret([REDACTED])")
+    }
+}
+
+impl<T: Zeroize> Display for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[REDACTED]")
+    }
+}
+
+impl<'de, T: Zeroize + Deserialize<'de>> Deserialize<'de> for Secret<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        T::deserialize(deserializer).map(Secret)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::cell::Cell;
+
+    use super::*;
+    use crate::lib::ToString;
+
+    #[test]
+    fn test_secret() {
+        let secret = SecretString::new("hunter2".to_string());
+        assert_eq!(secret.expose_secret(), "hunter2");
+        assert_eq!(format!("{secret:?}"), "Secret([REDACTED])");
+        assert_eq!(format!("{secret}"), "[REDACTED]");
+
+        #[derive(Debug, Serialize)]
+        struct Workload {
+            #[serde(serialize_with = "Secret::serialize_exposed")]
+            passphrase: SecretString,
+        }
+
+        let workload = Workload {
+            passphrase: secret.clone(),
+        };
+        assert!(!format!("{workload:?}").contains("hunter2"));
+        assert_eq!(
+            serde_json::to_string(&workload).unwrap(),
+            r#"{"passphrase":"hunter2"}"#
+        );
+    }
+
+    #[test]
+    fn test_secret_drop() {
+        struct Tracked<'a>(&'a Cell<bool>);
+
+        impl Zeroize for Tracked<'_> {
+            fn zeroize(&mut self) {
+                self.0.set(true);
+            }
+        }
+
+        fn zeroize_on_drop<T: ZeroizeOnDrop>(_: &T) {}
+
+        let zeroized = Cell::new(false);
+        let secret = Secret::new(Tracked(&zeroized));
+        zeroize_on_drop(&secret);
+        assert!(!zeroized.get());
+
+        drop(secret);
+        assert!(zeroized.get());
+    }
+}
@@ -0,0 +1,676 @@
+//! End-to-end secure channel between the guest and the KBS.
+//!
+//! The channel uses the Noise NK handshake (Noise_NK_25519_ChaChaPoly_SHA256):
+//! the guest knows the static X25519 public key of the KBS in advance (e.g.
+//! from its launch measurement), so only the KBS can complete the handshake,
+//! while the guest stays anonymous until it attests.
+//!
+//! Handshake and transport messages are carried as binary bodies of
+//! [`Request`]/[`Response`] sent to a dedicated KBS endpoint, so the host
+//! proxy keeps forwarding them as usual but never sees the cleartext. The KBS
+//! is expected to track the channel with the session cookie, like it does
+//! for the attestation session.
+
+use chacha20poly1305::{aead::AeadInPlace, ChaCha20Poly1305, KeyInit, Nonce};
+use hkdf::Hkdf;
+use rand_core::{CryptoRng, RngCore};
+use serde::{de::DeserializeOwned, Serialize};
+use sha2::{Digest, Sha256};
+use x25519_dalek::{PublicKey, ReusableSecret, SharedSecret, StaticSecret};
+use zeroize::Zeroize;
+
+use crate::{
+    client_proxy::{self, Connection, HttpMethod, Proxy, Request, Response},
+    lib::{fmt, Debug, String, Vec},
+};
+
+/// Name of the Noise protocol, which is also the initial handshake hash.
+pub const PROTOCOL_NAME: &[u8; 32] = b"Noise_NK_25519_ChaChaPoly_SHA256";
+
+/// Prologue mixed in the handshake, to bind it to this protocol.
+pub const PROLOGUE: &[u8] = b"reference-kbc secure channel v1";
+
+/// Default KBS endpoint receiving the secure channel messages.
+pub const DEFAULT_ENDPOINT: &str = "/kbs/v0/secure-channel";
+
+/// Content type of the secure channel messages.
+pub const CONTENT_TYPE: &str = "application/octet-stream";
+
+pub const KEY_SIZE: usize = 32;
+const HASH_SIZE: usize = 32;
+const TAG_SIZE: usize = 16;
+
+#[derive(Debug)]
+pub enum Error {
+    JsonError(serde_json::Error),
+    CP(client_proxy::Error),
+    HttpError(u16, String),
+    // Message too short, or out of order
+    InvalidMessage,
+    // Authentication of an encrypted message failed
+    DecryptError,
+    // The peer sent a low order X25519 public key
+    WeakKey,
+    // No more nonces available, the channel must be re-established
+    NonceExhausted,
+}
+
+impl Error {
+    /// Stable code of the error, see [`crate::Error::code`].
+    pub fn code(&self) -> u16 {
+        match self {
+            Self::JsonError(_) => 1,
+            Self::CP(_) => 2,
+            Self::HttpError(..) => 3,
+            Self::InvalidMessage => 4,
+            Self::DecryptError => 5,
+            Self::WeakKey => 6,
+            Self::NonceExhausted => 7,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::JsonError(e) => Some(e),
+            Self::CP(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::JsonError(je) => write!(f, "Malformed JSON - {je}"),
+            Self::CP(e) => write!(f, "Proxy error - {e}"),
+            Self::HttpError(status, body) => write!(f, "HTTP error code: {status} - {body}"),
+            Self::InvalidMessage => write!(f, "Invalid secure channel message"),
+            Self::DecryptError => write!(f, "Secure channel message authentication failed"),
+            Self::WeakKey => write!(f, "Peer sent a low order public key"),
+            Self::NonceExhausted => write!(f, "Secure channel nonces exhausted"),
+        }
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Self::JsonError(e)
+    }
+}
+
+impl From<client_proxy::Error> for Error {
+    fn from(e: client_proxy::Error) -> Self {
+        Self::CP(e)
+    }
+}
+
+impl From<Error> for crate::Error {
+    fn from(e: Error) -> Self {
+        Self::SC(e)
+    }
+}
+
+/// Returns the public key matching the X25519 `secret` key, e.g. to
+/// provision it to the guest.
+pub fn public_key(secret: &[u8; KEY_SIZE]) -> [u8; KEY_SIZE] {
+    PublicKey::from(&StaticSecret::from(*secret)).to_bytes()
+}
+
+fn check_shared(shared: SharedSecret) -> Result<SharedSecret, Error> {
+    if !shared.was_contributory() {
+        return Err(Error::WeakKey);
+    }
+
+    Ok(shared)
+}
+
+fn read_key(msg: &[u8]) -> Result<(PublicKey, &[u8]), Error> {
+    if msg.len() < KEY_SIZE {
+        return Err(Error::InvalidMessage);
+    }
+
+    let (key, rest) = msg.split_at(KEY_SIZE);
+    let mut bytes = [0u8; KEY_SIZE];
+    bytes.copy_from_slice(key);
+
+    Ok((PublicKey::from(bytes), rest))
+}
+
+/// Nonce of the message number `n`, which the caller only increments once
+/// the message is processed, so that a message failing authentication does
+/// not use it up.
+fn nonce(n: u64) -> Result<Nonce, Error> {
+    // The last nonce is reserved by the Noise specification
+    if n == u64::MAX {
+        return Err(Error::NonceExhausted);
+    }
+
+    let mut nonce = Nonce::default();
+    nonce[4..].copy_from_slice(&n.to_le_bytes());
+
+    Ok(nonce)
+}
+
+struct CipherState {
+    cipher: Option<ChaCha20Poly1305>,
+    n: u64,
+}
+
+impl CipherState {
+    fn new(key: Option<&[u8; KEY_SIZE]>) -> Self {
+        CipherState {
+            cipher: key.map(|key| ChaCha20Poly1305::new(key.into())),
+            n: 0,
+        }
+    }
+
+    /// Appends to `out` the encryption of `plaintext`, or `plaintext` itself
+    /// before a key is established.
+    fn encrypt(&mut self, ad: &[u8], plaintext: &[u8], out: &mut Vec<u8>) -> Result<(), Error> {
+        let start = out.len();
+        out.extend_from_slice(plaintext);
+
+        let Some(cipher) = &self.cipher else {
+            return Ok(());
+        };
+
+        let nonce = nonce(self.n)?;
+        let tag = cipher
+            .encrypt_in_place_detached(&nonce, ad, &mut out[start..])
+            .map_err(|_| Error::InvalidMessage)?;
+        out.extend_from_slice(&tag);
+        self.n += 1;
+
+        Ok(())
+    }
+
+    /// Appends to `out` the decryption of `ciphertext`.
+    fn decrypt(&mut self, ad: &[u8], ciphertext: &[u8], out: &mut Vec<u8>) -> Result<(), Error> {
+        let Some(cipher) = &self.cipher else {
+            out.extend_from_slice(ciphertext);
+            return Ok(());
+        };
+
+        let Some(len) = ciphertext.len().checked_sub(TAG_SIZE) else {
+            return Err(Error::InvalidMessage);
+        };
+        let (data, tag) = ciphertext.split_at(len);
+
+        let nonce = nonce(self.n)?;
+        let start = out.len();
+        out.extend_from_slice(data);
+        if cipher
+            .decrypt_in_place_detached(&nonce, ad, &mut out[start..], tag.into())
+            .is_err()
+        {
+            out.truncate(start);
+            return Err(Error::DecryptError);
+        }
+        self.n += 1;
+
+        Ok(())
+    }
+}
+
+struct SymmetricState {
+    ck: [u8; HASH_SIZE],
+    h: [u8; HASH_SIZE],
+    cs: CipherState,
+}
+
+impl SymmetricState {
+    fn new(prologue: &[u8]) -> Self {
+        let mut ss = SymmetricState {
+            ck: *PROTOCOL_NAME,
+            h: *PROTOCOL_NAME,
+            cs: CipherState::new(None),
+        };
+        ss.mix_hash(prologue);
+        ss
+    }
+
+    /// Noise HKDF with two outputs, which is HKDF-SHA256 with `ck` as salt and
+    /// empty info.
+    fn hkdf(&self, ikm: &[u8]) -> ([u8; HASH_SIZE], [u8; HASH_SIZE]) {
+        let mut okm = [0u8; 2 * HASH_SIZE];
+        Hkdf::<Sha256>::new(Some(&self.ck), ikm)
+            .expand(&[], &mut okm)
+            .expect("valid HKDF output length");
+
+        let mut out1 = [0u8; HASH_SIZE];
+        let mut out2 = [0u8; HASH_SIZE];
+        out1.copy_from_slice(&okm[..HASH_SIZE]);
+        out2.copy_from_slice(&okm[HASH_SIZE..]);
+        okm.zeroize();
+
+        (out1, out2)
+    }
+
+    fn mix_hash(&mut self, data: &[u8]) {
+        self.h = Sha256::new()
+            .chain_update(self.h)
+            .chain_update(data)
+            .finalize()
+            .into();
+    }
+
+    fn mix_key(&mut self, shared: &SharedSecret) {
+        let (mut ck, mut key) = self.hkdf(shared.as_bytes());
+        self.ck = ck;
+        self.cs = CipherState::new(Some(&key));
+        ck.zeroize();
+        key.zeroize();
+    }
+
+    fn encrypt_and_hash(&mut self, plaintext: &[u8], out: &mut Vec<u8>) -> Result<(), Error> {
+        let start = out.len();
+        self.cs.encrypt(&self.h, plaintext, out)?;
+        self.mix_hash(&out[start..]);
+        Ok(())
+    }
+
+    fn decrypt_and_hash(&mut self, ciphertext: &[u8], out: &mut Vec<u8>) -> Result<(), Error> {
+        self.cs.decrypt(&self.h, ciphertext, out)?;
+        self.mix_hash(ciphertext);
+        Ok(())
+    }
+
+    /// Returns the cipher states for the initiator and for the responder.
+    fn split(self) -> (CipherState, CipherState, [u8; HASH_SIZE]) {
+        let (mut k1, mut k2) = self.hkdf(&[]);
+        let ret = (
+            CipherState::new(Some(&k1)),
+            CipherState::new(Some(&k2)),
+            self.h,
+        );
+        k1.zeroize();
+        k2.zeroize();
+
+        ret
+    }
+}
+
+// The chaining key derives every key of the session
+impl Drop for SymmetricState {
+    fn drop(&mut self) {
+        self.ck.zeroize();
+        self.h.zeroize();
+    }
+}
+
+/// Guest side of the handshake.
+pub struct Initiator {
+    ss: SymmetricState,
+    rs: PublicKey,
+    e: Option<ReusableSecret>,
+}
+
+impl Initiator {
+    /// Starts a handshake with the peer whose static public key is
+    /// `remote_static`.
+    pub fn new(remote_static: &[u8; KEY_SIZE], prologue: &[u8]) -> Self {
+        let rs = PublicKey::from(*remote_static);
+        let mut ss = SymmetricState::new(prologue);
+        ss.mix_hash(rs.as_bytes());
+
+        Initiator { ss, rs, e: None }
+    }
+
+    /// Appends to `out` the first handshake message (`-> e, es`), carrying
+    /// `payload` encrypted.
+    pub fn write_message<R: RngCore + CryptoRng>(
+        &mut self,
+        rng: &mut R,
+        payload: &[u8],
+        out: &mut Vec<u8>,
+    ) -> Result<(), Error> {
+        if self.e.is_some() {
+            return Err(Error::InvalidMessage);
+        }
+
+        let e = ReusableSecret::random_from_rng(rng);
+        let e_pub = PublicKey::from(&e);
+        out.extend_from_slice(e_pub.as_bytes());
+        self.ss.mix_hash(e_pub.as_bytes());
+
+        let es = check_shared(e.diffie_hellman(&self.rs))?;
+        self.ss.mix_key(&es);
+        self.e = Some(e);
+
+        self.ss.encrypt_and_hash(payload, out)
+    }
+
+    /// Processes the second handshake message (`<- e, ee`), appending its
+    /// payload to `payload`, and completes the handshake.
+    pub fn read_message(mut self, msg: &[u8], payload: &mut Vec<u8>) -> Result<Transport, Error> {
+        let Some(e) = self.e.take() else {
+            return Err(Error::InvalidMessage);
+        };
+
+        let (re, msg) = read_key(msg)?;
+        self.ss.mix_hash(re.as_bytes());
+
+        let ee = check_shared(e.diffie_hellman(&re))?;
+        self.ss.mix_key(&ee);
+
+        self.ss.decrypt_and_hash(msg, payload)?;
+
+        let (tx, rx, h) = self.ss.split();
+        Ok(Transport { tx, rx, h })
+    }
+}
+
+/// KBS side of the handshake.
+pub struct Responder {
+    ss: SymmetricState,
+    s: StaticSecret,
+    re: Option<PublicKey>,
+}
+
+impl Responder {
+    /// Prepares to accept a handshake with the static `secret` key.
+    pub fn new(secret: &[u8; KEY_SIZE], prologue: &[u8]) -> Self {
+        let s = StaticSecret::from(*secret);
+        let mut ss = SymmetricState::new(prologue);
+        ss.mix_hash(PublicKey::from(&s).as_bytes());
+
+        Responder { ss, s, re: None }
+    }
+
+    /// Processes the first handshake message (`-> e, es`), appending its
+    /// payload to `payload`.
+    pub fn read_message(&mut self, msg: &[u8], payload: &mut Vec<u8>) -> Result<(), Error> {
+        if self.re.is_some() {
+            return Err(Error::InvalidMessage);
+        }
+
+        let (re, msg) = read_key(msg)?;
+        self.ss.mix_hash(re.as_bytes());
+
+        let es = check_shared(self.s.diffie_hellman(&re))?;
+        self.ss.mix_key(&es);
+
+        self.ss.decrypt_and_hash(msg, payload)?;
+        self.re = Some(re);
+
+        Ok(())
+    }
+
+    /// Appends to `out` the second handshake message (`<- e, ee`), carrying
+    /// `payload` encrypted, and completes the handshake.
+    pub fn write_message<R: RngCore + CryptoRng>(
+        mut self,
+        rng: &mut R,
+        payload: &[u8],
+        out: &mut Vec<u8>,
+    ) -> Result<Transport, Error> {
+        let Some(re) = self.re else {
+            return Err(Error::InvalidMessage);
+        };
+
+        let e = ReusableSecret::random_from_rng(rng);
+        let e_pub = PublicKey::from(&e);
+        out.extend_from_slice(e_pub.as_bytes());
+        self.ss.mix_hash(e_pub.as_bytes());
+
+        let ee = check_shared(e.diffie_hellman(&re))?;
+        self.ss.mix_key(&ee);
+
+        self.ss.encrypt_and_hash(payload, out)?;
+
+        let (rx, tx, h) = self.ss.split();
+        Ok(Transport { tx, rx, h })
+    }
+}
+
+/// Encryption of the messages exchanged after the handshake.
+///
+/// Each side must decrypt the messages in the order they were encrypted by
+/// the peer, so the channel must be established again if a message is lost.
+pub struct Transport {
+    tx: CipherState,
+    rx: CipherState,
+    h: [u8; HASH_SIZE],
+}
+
+impl Transport {
+    /// Hash of the whole handshake, which identifies the channel (e.g. to
+    /// bind it to the attestation evidence).
+    pub fn handshake_hash(&self) -> &[u8; HASH_SIZE] {
+        &self.h
+    }
+
+    pub fn encrypt(&mut self, plaintext: &[u8], out: &mut Vec<u8>) -> Result<(), Error> {
+        self.tx.encrypt(&[], plaintext, out)
+    }
+
+    pub fn decrypt(&mut self, ciphertext: &[u8], out: &mut Vec<u8>) -> Result<(), Error> {
+        self.rx.decrypt(&[], ciphertext, out)
+    }
+
+    /// Serializes and encrypts `msg`.
+    pub fn seal<T: Serialize + ?Sized>(&mut self, msg: &T) -> Result<Vec<u8>, Error> {
+        let plaintext = serde_json::to_vec(msg)?;
+        let mut out = Vec::with_capacity(plaintext.len() + TAG_SIZE);
+        self.encrypt(&plaintext, &mut out)?;
+        Ok(out)
+    }
+
+    /// Decrypts and deserializes a message sealed by the peer.
+    ///
+    /// The plaintext is zeroed afterwards, since the message may carry a
+    /// secret.
+    pub fn open<T: DeserializeOwned>(&mut self, ciphertext: &[u8]) -> Result<T, Error> {
+        let mut plaintext = Vec::with_capacity(ciphertext.len());
+        self.decrypt(ciphertext, &mut plaintext)?;
+
+        let ret = serde_json::from_slice(&plaintext);
+        plaintext.zeroize();
+
+        Ok(ret?)
+    }
+}
+
+/// Guest side of a secure channel to the KBS, tunnelled through a [`Proxy`].
+pub struct SecureChannel {
+    transport: Transport,
+    endpoint: String,
+}
+
+impl SecureChannel {
+    /// Establishes a channel with the KBS whose static public key is
+    /// `kbs_key`, through its secure channel `endpoint` (see
+    /// [`DEFAULT_ENDPOINT`]).
+    pub fn connect<C: Connection, R: RngCore + CryptoRng>(
+        proxy: &mut Proxy<C>,
+        endpoint: String,
+        kbs_key: &[u8; KEY_SIZE],
+        rng: &mut R,
+    ) -> Result<Self, Error> {
+        let mut initiator = Initiator::new(kbs_key, PROLOGUE);
+        let mut msg = Vec::new();
+        initiator.write_message(rng, &[], &mut msg)?;
+
+        let resp = proxy.request(&Self::outer_request(&endpoint, &msg))?;
+        if !resp.is_success() {
+            return Err(Error::HttpError(resp.status, resp.body));
+        }
+
+        let transport = initiator.read_message(&resp.body_bytes()?, &mut Vec::new())?;
+
+        Ok(SecureChannel {
+            transport,
+            endpoint,
+        })
+    }
+
+    fn outer_request(endpoint: &str, body: &[u8]) -> Request {
+        Request::with_bytes(endpoint.into(), HttpMethod::POST, body).content_type(CONTENT_TYPE)
+    }
+
+    pub fn transport(&self) -> &Transport {
+        &self.transport
+    }
+
+    /// Sends `req` to the KBS through the channel and returns its response.
+    ///
+    /// The proxy only sees a POST to the channel endpoint with an opaque
+    /// body, and an opaque response.
+    pub fn request<C: Connection>(
+        &mut self,
+        proxy: &mut Proxy<C>,
+        req: &Request,
+    ) -> Result<Response, Error> {
+        let msg = self.transport.seal(req)?;
+
+        let resp = proxy.request(&Self::outer_request(&self.endpoint, &msg))?;
+        if !resp.is_success() {
+            return Err(Error::HttpError(resp.status, resp.body));
+        }
+
+        self.transport.open(&resp.body_bytes()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::net::UnixStream;
+
+    use rand::rngs::OsRng;
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::client_proxy::{tests::spawn_peer, unix::UnixConnection, SUPPORTED_CAPABILITIES};
+
+    const KBS_SECRET: [u8; KEY_SIZE] = [7u8; KEY_SIZE];
+
+    fn handshake() -> (Transport, Transport) {
+        let mut initiator = Initiator::new(&public_key(&KBS_SECRET), PROLOGUE);
+        let mut responder = Responder::new(&KBS_SECRET, PROLOGUE);
+
+        let mut msg1 = Vec::new();
+        initiator
+            .write_message(&mut OsRng, b"hello", &mut msg1)
+            .unwrap();
+        let mut payload = Vec::new();
+        responder.read_message(&msg1, &mut payload).unwrap();
+        assert_eq!(payload, b"hello");
+
+        let mut msg2 = Vec::new();
+        let kbs = responder
+            .write_message(&mut OsRng, b"world", &mut msg2)
+            .unwrap();
+        let mut payload = Vec::new();
+        let guest = initiator.read_message(&msg2, &mut payload).unwrap();
+        assert_eq!(payload, b"world");
+
+        (guest, kbs)
+    }
+
+    #[test]
+    fn test_handshake() {
+        let (mut guest, mut kbs) = handshake();
+        assert_eq!(guest.handshake_hash(), kbs.handshake_hash());
+
+        for i in 0..3 {
+            let ct = guest.seal(&json!({ "seq": i })).unwrap();
+            assert_eq!(kbs.open::<Value>(&ct).unwrap(), json!({ "seq": i }));
+
+            let ct = kbs.seal("reply").unwrap();
+            assert_eq!(guest.open::<String>(&ct).unwrap(), "reply");
+        }
+
+        // Tampered and replayed messages are rejected, without breaking the
+        // channel for the genuine ones
+        let ct = guest.seal("data").unwrap();
+        let mut tampered = ct.clone();
+        tampered[0] ^= 1;
+        assert!(matches!(
+            kbs.open::<String>(&tampered),
+            Err(Error::DecryptError)
+        ));
+        assert_eq!(kbs.open::<String>(&ct).unwrap(), "data");
+        assert!(matches!(kbs.open::<String>(&ct), Err(Error::DecryptError)));
+
+        let ct = guest.seal("more").unwrap();
+        assert_eq!(kbs.open::<String>(&ct).unwrap(), "more");
+    }
+
+    #[test]
+    fn test_wrong_kbs_key() {
+        let mut initiator = Initiator::new(&public_key(&[8u8; KEY_SIZE]), PROLOGUE);
+        let mut responder = Responder::new(&KBS_SECRET, PROLOGUE);
+
+        let mut msg1 = Vec::new();
+        initiator
+            .write_message(&mut OsRng, b"hello", &mut msg1)
+            .unwrap();
+        assert!(matches!(
+            responder.read_message(&msg1, &mut Vec::new()),
+            Err(Error::DecryptError)
+        ));
+
+        // A low order point as ephemeral key
+        let mut responder = Responder::new(&KBS_SECRET, PROLOGUE);
+        assert!(matches!(
+            responder.read_message(&[0u8; KEY_SIZE + TAG_SIZE], &mut Vec::new()),
+            Err(Error::WeakKey)
+        ));
+    }
+
+    #[test]
+    fn test_secure_channel() {
+        let (socket, remote_socket) = UnixStream::pair().unwrap();
+
+        // Proxy and KBS: the proxy forwards opaque bodies
+        let host = spawn_peer(
+            move || UnixConnection(remote_socket),
+            move |proxy| {
+                let mut responder = Responder::new(&KBS_SECRET, PROLOGUE);
+                let outer: Request = proxy.read_message().unwrap();
+                assert_eq!(outer.endpoint, DEFAULT_ENDPOINT);
+                responder
+                    .read_message(&outer.body_bytes().unwrap(), &mut Vec::new())
+                    .unwrap();
+
+                let mut msg = Vec::new();
+                let mut transport = responder.write_message(&mut OsRng, &[], &mut msg).unwrap();
+                let mut resp = Response::new(200, String::new());
+                resp.set_body(&msg);
+                resp.id = outer.id;
+                proxy.write_message(&resp).unwrap();
+
+                let outer: Request = proxy.read_message().unwrap();
+                assert!(!String::from_utf8_lossy(&outer.body_bytes().unwrap()).contains("/secret"));
+                let req: Request = transport.open(&outer.body_bytes().unwrap()).unwrap();
+                assert_eq!(req.endpoint, "/secret");
+
+                let inner = Response::new(200, "top secret".into());
+                let mut resp = Response::new(200, String::new());
+                resp.set_body(&transport.seal(&inner).unwrap());
+                resp.id = outer.id;
+                proxy.write_message(&resp).unwrap();
+            },
+        );
+
+        let mut proxy = Proxy::new(UnixConnection(socket));
+        proxy.handshake(SUPPORTED_CAPABILITIES).unwrap();
+
+        let mut channel = SecureChannel::connect(
+            &mut proxy,
+            DEFAULT_ENDPOINT.into(),
+            &public_key(&KBS_SECRET),
+            &mut OsRng,
+        )
+        .unwrap();
+        let resp = channel
+            .request(&mut proxy, &Request::get("/secret".into()))
+            .unwrap();
+        assert_eq!(resp.body, "top secret");
+
+        host.join().unwrap();
+    }
+}